@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use dot_http::output::parse_format;
+use dot_http::output::print::FormattedOutputter;
+use dot_http::{ClientConfig, Runtime, ScriptConfig};
+use httpmock::MockServer;
+use std::io::{sink, Write};
+use tempfile::NamedTempFile;
+
+const REQUESTS: usize = 500;
+const VARIABLES: usize = 200;
+
+fn create_file(contents: &str) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    write!(file, "{}", contents).unwrap();
+    file
+}
+
+fn execute_all(c: &mut Criterion) {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(httpmock::Method::GET);
+        then.status(200).body(r#"{"value": true}"#);
+    });
+
+    let variables = (0..VARIABLES)
+        .map(|index| format!(r#""var{}": "value{}""#, index, index))
+        .collect::<Vec<String>>()
+        .join(",");
+    let env_file = create_file(&format!(r#"{{"dev": {{{}}}}}"#, variables));
+    let script = (0..REQUESTS)
+        .map(|index| {
+            format!(
+                "GET http://localhost:{}/items/{}?v={{{{var{}}}}}\n\n> {{% client.global.set('last', response.body.value); %}}\n",
+                server.port(),
+                index,
+                index % VARIABLES
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n###\n\n");
+    let script_file = create_file(&script);
+
+    let mut group = c.benchmark_group("runtime");
+    group.sample_size(10);
+    group.bench_function("execute_500_requests", |b| {
+        b.iter(|| {
+            let snapshot_file = create_file("{}");
+            let mut writer = sink();
+            let mut outputter = FormattedOutputter::new(
+                &mut writer,
+                parse_format("").unwrap(),
+                parse_format("").unwrap(),
+            );
+            let mut runtime = Runtime::new(
+                "dev",
+                snapshot_file.path(),
+                env_file.path(),
+                &mut outputter,
+                ClientConfig::default(),
+                ScriptConfig::default(),
+            )
+            .unwrap();
+            runtime.execute(script_file.path(), 1, true).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, execute_all);
+criterion_main!(benches);
@@ -12,6 +12,8 @@ main() {
 
     cross test --target $TARGET
     cross test --target $TARGET --release
+    cross test --target $TARGET --no-default-features --features quickjs
+    cross test --target $TARGET --features rhai
 }
 
 # we don't run the "test phase" when doing deploys
@@ -0,0 +1,268 @@
+use crate::Result;
+use anyhow::Context;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(test)]
+mod tests;
+
+/// A cookie as kept in the jar, and as scripts see it in `client.cookies`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// The host the cookie was set by, or the domain it was set for, every host when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Whether the cookie is only sent to the host that set it, and not to its subdomains
+    #[serde(default)]
+    pub host_only: bool,
+    #[serde(default = "root")]
+    pub path: String,
+    /// When the cookie expires, in seconds since the epoch, it lasts until removed when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub http_only: bool,
+}
+
+fn root() -> String {
+    "/".to_string()
+}
+
+impl Cookie {
+    /// Reads a `Set-Cookie` header received from `url`, `None` when it is invalid or its domain
+    /// doesn't cover the host
+    pub fn parse(set_cookie: &str, url: &Url) -> Option<Cookie> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let mut attributes = set_cookie.split(';');
+        let (name, value) = attributes.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.trim().trim_matches('"').to_string(),
+            domain: Some(host.clone()),
+            host_only: true,
+            path: default_path(url),
+            expires: None,
+            secure: false,
+            http_only: false,
+        };
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (attribute.trim(), ""),
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    cookie.domain = Some(domain);
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" => {
+                    if let Ok(expires) = httpdate::parse_http_date(value) {
+                        cookie.expires = Some(seconds(expires));
+                    }
+                }
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                _ => {}
+            }
+        }
+        // Max-Age takes precedence over Expires
+        if let Some(max_age) = max_age {
+            let now = seconds(SystemTime::now());
+            cookie.expires = Some(if max_age <= 0 {
+                0
+            } else {
+                now + max_age as u64
+            });
+        }
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        let domain = match &self.domain {
+            None => true,
+            Some(domain) if self.host_only => host == *domain,
+            Some(domain) => domain_matches(&host, domain),
+        };
+        domain && path_matches(url.path(), &self.path) && (!self.secure || url.scheme() == "https")
+    }
+
+    fn same_as(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The directory of the request path, which a cookie set without a path is sent to
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => root(),
+        Some(index) => url.path()[..index].to_string(),
+    }
+}
+
+/// The cookies received during a run, sent back with the requests they match
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Keeps the cookies of the `Set-Cookie` headers of a response, removing the ones they expire
+    pub fn store(&mut self, url: &Url, headers: &[(String, String)]) {
+        let set_cookies = headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"));
+        for (_, set_cookie) in set_cookies {
+            if let Some(cookie) = Cookie::parse(set_cookie, url) {
+                self.cookies.retain(|stored| !stored.same_as(&cookie));
+                self.cookies.push(cookie);
+            }
+        }
+        self.remove_expired();
+    }
+
+    /// The `Cookie` header of a request to `url`, if any cookie matches it
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let now = seconds(SystemTime::now());
+        let cookies: Vec<_> = self
+            .cookies
+            .iter()
+            .filter(|cookie| !cookie.is_expired(now) && cookie.matches(url))
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        if cookies.is_empty() {
+            None
+        } else {
+            Some(cookies.join("; "))
+        }
+    }
+
+    /// The cookies as the JSON array scripts see as `client.cookies`
+    pub fn to_script(&self) -> String {
+        serde_json::to_string(&self.cookies).unwrap()
+    }
+
+    /// Replaces the cookies with the ones a script left in `client.cookies`
+    pub fn set_script(&mut self, script: &str) -> Result<()> {
+        self.cookies = serde_json::from_str(script).with_context(|| "Invalid cookies")?;
+        self.remove_expired();
+        Ok(())
+    }
+
+    fn remove_expired(&mut self) {
+        let now = seconds(SystemTime::now());
+        self.cookies.retain(|cookie| !cookie.is_expired(now));
+    }
+}
+
+/// The file the cookie jar of each environment is kept in between runs
+///
+/// ```text
+/// {"dev": [{"name": "session", "value": "...", "domain": "localhost", "hostOnly": true, ...}]}
+/// ```
+#[derive(Debug)]
+pub struct CookieJarFile {
+    path: PathBuf,
+    env: String,
+}
+
+impl CookieJarFile {
+    pub fn new(path: &Path, env: &str) -> Self {
+        CookieJarFile {
+            path: path.to_path_buf(),
+            env: env.to_string(),
+        }
+    }
+
+    /// The cookie jar of the environment, empty when the file doesn't exist yet
+    pub fn load(&self) -> Result<CookieJar> {
+        let mut jar = CookieJar::default();
+        if let Some(cookies) = self.read()?.remove(&self.env) {
+            jar.cookies = serde_json::from_value(cookies)
+                .with_context(|| format!("Failed parsing cookie jar: {:?}", self.path))?;
+            jar.remove_expired();
+        }
+        Ok(jar)
+    }
+
+    /// Writes the cookie jar of the environment, keeping those of the other environments, through
+    /// a temporary file renamed over the cookie jar
+    pub fn save(&self, jar: &CookieJar) -> Result<()> {
+        let mut environments = self.read()?;
+        environments.insert(self.env.clone(), serde_json::to_value(&jar.cookies)?);
+        let script = serde_json::to_string(&Value::Object(environments))?;
+
+        let mut temporary = self.path.file_name().unwrap_or_default().to_os_string();
+        temporary.push(format!(".{}.tmp", std::process::id()));
+        let temporary = self.path.with_file_name(temporary);
+        let written = File::create(&temporary).and_then(|mut file| {
+            file.write_all(script.as_bytes())?;
+            file.sync_all()
+        });
+        match written.and_then(|_| std::fs::rename(&temporary, &self.path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = std::fs::remove_file(&temporary);
+                Err(e).with_context(|| format!("Failed writing cookie jar: {:?}", self.path))
+            }
+        }
+    }
+
+    fn read(&self) -> Result<Map<String, Value>> {
+        match read_to_string(&self.path) {
+            Ok(script) => match serde_json::from_str(&script) {
+                Ok(Value::Object(environments)) => Ok(environments),
+                _ => Err(anyhow!(
+                    "Failed parsing cookie jar: {:?}, expected an object of environments",
+                    self.path
+                )),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Map::new()),
+            Err(e) => Err(e).with_context(|| format!("Failed opening cookie jar: {:?}", self.path)),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use super::*;
+
+fn url(url: &str) -> Url {
+    Url::parse(url).unwrap()
+}
+
+fn set_cookies(values: &[&str]) -> Vec<(String, String)> {
+    values
+        .iter()
+        .map(|value| ("set-cookie".to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_parse() {
+    let cookie = Cookie::parse(
+        "session=abc; Path=/api; Secure; HttpOnly",
+        &url("https://Example.com/login"),
+    )
+    .unwrap();
+    assert_eq!(
+        cookie,
+        Cookie {
+            name: "session".to_string(),
+            value: "abc".to_string(),
+            domain: Some("example.com".to_string()),
+            host_only: true,
+            path: "/api".to_string(),
+            expires: None,
+            secure: true,
+            http_only: true,
+        }
+    );
+
+    let cookie = Cookie::parse(
+        "id=7; Domain=.example.com; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+        &url("http://api.example.com/users/7"),
+    )
+    .unwrap();
+    assert_eq!(cookie.domain, Some("example.com".to_string()));
+    assert!(!cookie.host_only);
+    assert_eq!(cookie.path, "/users");
+    assert_eq!(cookie.expires, Some(1445412480));
+
+    assert_eq!(
+        Cookie::parse("id=7; Domain=other.com", &url("http://example.com/")),
+        None
+    );
+    assert_eq!(Cookie::parse("invalid", &url("http://example.com/")), None);
+}
+
+#[test]
+fn test_header() {
+    let mut jar = CookieJar::default();
+    jar.store(
+        &url("http://api.example.com/login"),
+        &set_cookies(&[
+            "session=abc",
+            "shared=1; Domain=example.com",
+            "admin=1; Path=/admin",
+            "secure=1; Secure",
+        ]),
+    );
+
+    assert_eq!(
+        jar.header(&url("http://api.example.com/users")),
+        Some("session=abc; shared=1".to_string())
+    );
+    assert_eq!(
+        jar.header(&url("https://api.example.com/admin/users")),
+        Some("session=abc; shared=1; admin=1; secure=1".to_string())
+    );
+    assert_eq!(
+        jar.header(&url("http://www.example.com/")),
+        Some("shared=1".to_string())
+    );
+    assert_eq!(jar.header(&url("http://other.com/")), None);
+}
+
+#[test]
+fn test_store_replaces_and_expires() {
+    let mut jar = CookieJar::default();
+    let login = url("http://localhost/login");
+    jar.store(&login, &set_cookies(&["session=abc", "theme=dark"]));
+    jar.store(&login, &set_cookies(&["session=def", "theme=; Max-Age=0"]));
+
+    assert_eq!(
+        jar.header(&url("http://localhost/")),
+        Some("session=def".to_string())
+    );
+}
+
+#[test]
+fn test_script() {
+    let mut jar = CookieJar::default();
+    jar.set_script(r#"[{"name": "token", "value": "abc"}]"#)
+        .unwrap();
+
+    assert_eq!(
+        jar.header(&url("http://any.host/path")),
+        Some("token=abc".to_string())
+    );
+    assert_eq!(
+        jar.to_script(),
+        r#"[{"name":"token","value":"abc","hostOnly":false,"path":"/","secure":false,"httpOnly":false}]"#
+    );
+    assert!(jar.set_script(r#"[{"value": "abc"}]"#).is_err());
+}
+
+#[test]
+fn test_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cookies.json");
+    let dev = CookieJarFile::new(&path, "dev");
+    let prod = CookieJarFile::new(&path, "prod");
+    assert_eq!(dev.load().unwrap(), CookieJar::default());
+
+    let mut jar = CookieJar::default();
+    jar.store(&url("http://localhost/"), &set_cookies(&["session=dev"]));
+    dev.save(&jar).unwrap();
+    let mut jar = CookieJar::default();
+    jar.store(&url("http://localhost/"), &set_cookies(&["session=prod"]));
+    prod.save(&jar).unwrap();
+
+    assert_eq!(
+        dev.load().unwrap().header(&url("http://localhost/")),
+        Some("session=dev".to_string())
+    );
+    assert_eq!(prod.load().unwrap(), jar);
+}
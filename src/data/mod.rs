@@ -0,0 +1,101 @@
+use crate::Result;
+use anyhow::Context;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+mod tests;
+
+/// The rows a request is run with, read from a CSV file with a header row or from a JSON file
+/// holding an array of objects
+#[derive(Debug, PartialEq)]
+pub struct Data {
+    file: PathBuf,
+    rows: Vec<Map<String, Value>>,
+}
+
+/// A run of the requests with one row of a data file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Iteration {
+    /// The name of the data file
+    pub file: String,
+    /// The row, counting from 1
+    pub row: usize,
+    pub rows: usize,
+}
+
+impl fmt::Display for Iteration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} row {}/{}", self.file, self.row, self.rows)
+    }
+}
+
+impl Data {
+    /// Reads a data file, its format is picked by its extension
+    pub fn load(path: &Path) -> Result<Data> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed opening data file: {:?}", path))?;
+        let rows = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => parse_csv(&source),
+            Some("json") => parse_json(&source),
+            _ => Err(anyhow!("Expected a .csv or .json data file")),
+        }
+        .with_context(|| format!("Failed parsing data file: {:?}", path))?;
+        Ok(Data {
+            file: path.to_path_buf(),
+            rows,
+        })
+    }
+
+    /// Every row along with the iteration it makes
+    pub fn iterations(&self) -> impl Iterator<Item = (Iteration, &Map<String, Value>)> {
+        let file = self
+            .file
+            .file_name()
+            .unwrap_or(self.file.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+        let rows = self.rows.len();
+        self.rows.iter().enumerate().map(move |(index, row)| {
+            let iteration = Iteration {
+                file: file.clone(),
+                row: index + 1,
+                rows,
+            };
+            (iteration, row)
+        })
+    }
+}
+
+/// The columns of a CSV file are named by its header row, every value is a string
+fn parse_csv(source: &str) -> Result<Vec<Map<String, Value>>> {
+    let mut reader = csv::Reader::from_reader(source.as_bytes());
+    let headers = reader.headers()?.clone();
+    reader
+        .records()
+        .map(|record| {
+            Ok(headers
+                .iter()
+                .zip(record?.iter())
+                .map(|(name, value)| (name.to_string(), Value::String(value.to_string())))
+                .collect())
+        })
+        .collect()
+}
+
+fn parse_json(source: &str) -> Result<Vec<Map<String, Value>>> {
+    match serde_json::from_str(source)? {
+        Value::Array(rows) => rows
+            .into_iter()
+            .map(|row| match row {
+                Value::Object(row) => Ok(row),
+                row => Err(anyhow!(
+                    "Expected every row to be an object, found: {}",
+                    row
+                )),
+            })
+            .collect(),
+        _ => Err(anyhow!("Expected an array of objects")),
+    }
+}
@@ -0,0 +1,77 @@
+use super::*;
+use serde_json::json;
+
+fn load(name: &str, source: &str) -> Result<Data> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(name);
+    std::fs::write(&path, source).unwrap();
+    Data::load(&path)
+}
+
+fn rows(data: &Data) -> Vec<(String, Value)> {
+    data.iterations()
+        .map(|(iteration, row)| (iteration.to_string(), Value::Object(row.clone())))
+        .collect()
+}
+
+#[test]
+fn test_load_csv() {
+    let data = load("rows.csv", "name,age\nada,36\n\"lovelace, a\",\n").unwrap();
+
+    assert_eq!(
+        rows(&data),
+        vec![
+            (
+                "rows.csv row 1/2".to_string(),
+                json!({"name": "ada", "age": "36"})
+            ),
+            (
+                "rows.csv row 2/2".to_string(),
+                json!({"name": "lovelace, a", "age": ""})
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_load_json() {
+    let data = load("rows.json", r#"[{"id": 1, "tags": ["a"]}, {"id": 2}]"#).unwrap();
+
+    assert_eq!(
+        rows(&data),
+        vec![
+            (
+                "rows.json row 1/2".to_string(),
+                json!({"id": 1, "tags": ["a"]})
+            ),
+            ("rows.json row 2/2".to_string(), json!({"id": 2})),
+        ]
+    );
+}
+
+#[test]
+fn test_load_errors() {
+    let error = load("rows.json", r#"{"id": 1}"#).unwrap_err();
+    assert_eq!(
+        error.root_cause().to_string(),
+        "Expected an array of objects"
+    );
+
+    let error = load("rows.json", "[1]").unwrap_err();
+    assert_eq!(
+        error.root_cause().to_string(),
+        "Expected every row to be an object, found: 1"
+    );
+
+    let error = load("rows.txt", "").unwrap_err();
+    assert_eq!(
+        error.root_cause().to_string(),
+        "Expected a .csv or .json data file"
+    );
+
+    let error = Data::load(Path::new("missing.csv")).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Failed opening data file: \"missing.csv\""
+    );
+}
@@ -0,0 +1,231 @@
+use crate::proxy::SECTION as PROXY;
+use crate::secrets::SECTION as SECRETS;
+use crate::Result;
+use anyhow::Context;
+use serde_json::{Map, Value};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+mod tests;
+
+/// The environment every other environment inherits from
+pub const SHARED: &str = "$shared";
+
+/// What the environment files declare for a run
+#[derive(Debug)]
+pub struct Environment {
+    /// The environment script the engines expect, `{"<env>": {...}}`
+    pub script: String,
+    /// The `secrets` sections of both files, the private one taking precedence
+    pub secrets: Option<Value>,
+    /// The `$proxy` section of the environment, taken out of its variables
+    pub proxy: Option<Value>,
+}
+
+/// Reads the environment file along with its private counterpart and resolves `env`. The public
+/// file is created when it doesn't exist yet.
+pub fn load(env_file: &Path, env: &str) -> Result<Environment> {
+    let public = match read_file(env_file)? {
+        Some(public) => public,
+        None => {
+            std::fs::write(env_file, "{}")
+                .with_context(|| format!("Failed creating environment file: {:?}", env_file))?;
+            Value::Object(Map::new())
+        }
+    };
+    let private = read_file(&private_file(env_file))?.unwrap_or_else(|| Value::Object(Map::new()));
+
+    let mut environments = Map::new();
+    let mut proxy = None;
+    if let Some(mut environment) = resolve(&public, &private, env) {
+        proxy = environment
+            .as_object_mut()
+            .and_then(|object| object.remove(PROXY));
+        environments.insert(env.to_string(), environment);
+    }
+    let secrets = match (public.get(SECRETS), private.get(SECRETS)) {
+        (Some(public), Some(private)) => {
+            let mut secrets = public.clone();
+            merge(&mut secrets, private);
+            Some(secrets)
+        }
+        (public, private) => private.or(public).cloned(),
+    };
+    Ok(Environment {
+        script: Value::Object(environments).to_string(),
+        secrets,
+        proxy,
+    })
+}
+
+/// The layers of an environment, see [`resolve`], along with the file each comes from
+pub fn layers(env_file: &Path, env: &str) -> Result<Vec<(PathBuf, String, Value)>> {
+    let private_file = private_file(env_file);
+    let public = read_file(env_file)?.unwrap_or_else(|| Value::Object(Map::new()));
+    let private = read_file(&private_file)?.unwrap_or_else(|| Value::Object(Map::new()));
+    let layers = [
+        (env_file, SHARED, public.get(SHARED)),
+        (private_file.as_path(), SHARED, private.get(SHARED)),
+        (env_file, env, public.get(env)),
+        (private_file.as_path(), env, private.get(env)),
+    ];
+    Ok(layers
+        .iter()
+        .filter_map(|(file, name, layer)| {
+            let mut layer = (*layer)?.clone();
+            if let Some(object) = layer.as_object_mut() {
+                object.remove(PROXY);
+            }
+            Some((file.to_path_buf(), name.to_string(), layer))
+        })
+        .collect())
+}
+
+/// Parses an environment file, `None` when it doesn't exist
+fn read_file(path: &Path) -> Result<Option<Value>> {
+    match read_to_string(path) {
+        Ok(script) => parse(path, &script).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed opening environment file: {:?}", path)),
+    }
+}
+
+fn parse(path: &Path, script: &str) -> Result<Value> {
+    serde_json::from_str(script)
+        .with_context(|| format!("Failed parsing environment file: {:?}", path))
+}
+
+/// `http-client.private.env.json` for `http-client.env.json`, other names get `.private` added
+/// before their extension
+pub fn private_file(env_file: &Path) -> PathBuf {
+    let name = env_file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let private = match name.strip_suffix(".env.json") {
+        Some(stem) => format!("{}.private.env.json", stem),
+        None => match name.rsplit_once('.') {
+            Some((stem, extension)) => format!("{}.private.{}", stem, extension),
+            None => format!("{}.private", name),
+        },
+    };
+    env_file.with_file_name(private)
+}
+
+/// Merges the layers of an environment, later layers taking precedence: the public `$shared`, the
+/// private `$shared`, the public environment, then the private environment. `None` when none of
+/// them exist.
+pub fn resolve(public: &Value, private: &Value, env: &str) -> Option<Value> {
+    let layers = [
+        public.get(SHARED),
+        private.get(SHARED),
+        public.get(env),
+        private.get(env),
+    ];
+    layers
+        .iter()
+        .flatten()
+        .fold(None, |merged, layer| match merged {
+            None => Some((*layer).clone()),
+            Some(mut merged) => {
+                merge(&mut merged, layer);
+                Some(merged)
+            }
+        })
+}
+
+/// Deep merges `overlay` into `base`, objects are merged key by key while any other value replaces
+/// what it overlays
+pub fn merge(base: &mut Value, overlay: &Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+/// Loads a dotenv file into the process environment, variables the process already has are kept
+pub fn load_dotenv(path: &Path) -> Result<()> {
+    let script =
+        read_to_string(path).with_context(|| format!("Failed opening dotenv file: {:?}", path))?;
+    for (name, value) in
+        parse_dotenv(&script).with_context(|| format!("Failed parsing dotenv file: {:?}", path))?
+    {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Parses `NAME=value` lines, optionally prefixed with `export`. Values may be single quoted,
+/// taken as is, or double quoted, where `\n`, `\"` and `\\` are unescaped. Blank lines and lines
+/// starting with `#` are skipped, as are comments after unquoted values.
+pub fn parse_dotenv(script: &str) -> Result<Vec<(String, String)>> {
+    let mut variables = vec![];
+    for (index, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected NAME=value on line {}", index + 1))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(anyhow!(
+                "Invalid variable name on line {}: {}",
+                index + 1,
+                name
+            ));
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted
+                .strip_suffix('\'')
+                .ok_or_else(|| anyhow!("Unterminated quote on line {}", index + 1))?
+                .to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted
+                .strip_suffix('"')
+                .ok_or_else(|| anyhow!("Unterminated quote on line {}", index + 1))?;
+            unescape(quoted)
+        } else {
+            match value.find(" #") {
+                Some(comment) => value[..comment].trim_end().to_string(),
+                None => value.to_string(),
+            }
+        };
+        variables.push((name.to_string(), value));
+    }
+    Ok(variables)
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
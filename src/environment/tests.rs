@@ -0,0 +1,225 @@
+use super::*;
+use serde_json::json;
+
+#[test]
+fn test_private_file() {
+    assert_eq!(
+        private_file(Path::new("/api/http-client.env.json")),
+        PathBuf::from("/api/http-client.private.env.json")
+    );
+    assert_eq!(
+        private_file(Path::new("env.json")),
+        PathBuf::from("env.private.json")
+    );
+    assert_eq!(private_file(Path::new("env")), PathBuf::from("env.private"));
+}
+
+#[test]
+fn test_resolve_precedence() {
+    let public = json!({
+        "$shared": {"host": "shared", "token": "shared", "timeout": 10},
+        "dev": {"host": "dev"}
+    });
+    let private = json!({
+        "$shared": {"token": "private-shared"},
+        "dev": {"token": "private-dev"}
+    });
+
+    let dev = resolve(&public, &private, "dev").unwrap();
+
+    assert_eq!(
+        dev,
+        json!({"host": "dev", "token": "private-dev", "timeout": 10})
+    );
+}
+
+#[test]
+fn test_resolve_deep_merge() {
+    let public = json!({
+        "$shared": {"auth": {"user": "shared", "scopes": ["read"]}},
+        "dev": {"auth": {"realm": "dev"}}
+    });
+    let private = json!({"dev": {"auth": {"password": "secret", "scopes": ["write"]}}});
+
+    let dev = resolve(&public, &private, "dev").unwrap();
+
+    assert_eq!(
+        dev,
+        json!({"auth": {"user": "shared", "scopes": ["write"], "realm": "dev", "password": "secret"}})
+    );
+}
+
+#[test]
+fn test_resolve_shared_only() {
+    let public = json!({"$shared": {"host": "shared"}});
+
+    assert_eq!(
+        resolve(&public, &json!({}), "prod"),
+        Some(json!({"host": "shared"}))
+    );
+    assert_eq!(resolve(&json!({"dev": {}}), &json!({}), "prod"), None);
+}
+
+#[test]
+fn test_load() {
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+    std::fs::write(
+        &env_file,
+        r#"{"$shared": {"host": "example.com"}, "dev": {"token": "public"}}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("http-client.private.env.json"),
+        r#"{"dev": {"token": "private"}}"#,
+    )
+    .unwrap();
+
+    let script: Value = serde_json::from_str(&load(&env_file, "dev").unwrap().script).unwrap();
+
+    assert_eq!(
+        script,
+        json!({"dev": {"host": "example.com", "token": "private"}})
+    );
+}
+
+#[test]
+fn test_layers() {
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+    std::fs::write(&env_file, r#"{"$shared": {"a": 1}, "dev": {"b": 2}}"#).unwrap();
+    let private_file = dir.path().join("http-client.private.env.json");
+    std::fs::write(&private_file, r#"{"dev": {"c": 3}}"#).unwrap();
+
+    assert_eq!(
+        layers(&env_file, "dev").unwrap(),
+        vec![
+            (env_file.clone(), SHARED.to_string(), json!({"a": 1})),
+            (env_file.clone(), "dev".to_string(), json!({"b": 2})),
+            (private_file, "dev".to_string(), json!({"c": 3})),
+        ]
+    );
+    assert!(layers(&dir.path().join("missing.env.json"), "dev")
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_load_creates_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+
+    assert_eq!(load(&env_file, "dev").unwrap().script, "{}");
+    assert_eq!(std::fs::read_to_string(&env_file).unwrap(), "{}");
+}
+
+#[test]
+fn test_load_proxy() {
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+    std::fs::write(
+        &env_file,
+        r#"{"$shared": {"$proxy": {"url": "http://proxy:3128"}}, "dev": {"host": "localhost"}}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("http-client.private.env.json"),
+        r#"{"dev": {"$proxy": {"password": "s3cr3t"}}}"#,
+    )
+    .unwrap();
+
+    let environment = load(&env_file, "dev").unwrap();
+
+    assert_eq!(environment.script, r#"{"dev":{"host":"localhost"}}"#);
+    assert_eq!(
+        environment.proxy,
+        Some(json!({"url": "http://proxy:3128", "password": "s3cr3t"}))
+    );
+    assert!(layers(&env_file, "dev")
+        .unwrap()
+        .iter()
+        .all(|(_, _, layer)| layer.get("$proxy").is_none()));
+}
+
+#[test]
+fn test_load_secrets() {
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+    std::fs::write(
+        &env_file,
+        r#"{"secrets": {"token": {"command": "pass show token"}, "key": {"file": "key.txt"}}}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("http-client.private.env.json"),
+        r#"{"secrets": {"token": {"command": "op read token"}}, "dev": {}}"#,
+    )
+    .unwrap();
+
+    let environment = load(&env_file, "dev").unwrap();
+
+    assert_eq!(environment.script, r#"{"dev":{}}"#);
+    assert_eq!(
+        environment.secrets,
+        Some(json!({"token": {"command": "op read token"}, "key": {"file": "key.txt"}}))
+    );
+}
+
+#[test]
+fn test_parse_dotenv() {
+    let script = r#"
+# Tokens for CI
+TOKEN=abc123
+export HOST = example.com
+EMPTY=
+COMMENTED=value # trailing comment
+SINGLE='raw \n # kept'
+DOUBLE="line\nbreak \"quoted\""
+"#;
+
+    let variables = parse_dotenv(script).unwrap();
+
+    assert_eq!(
+        variables,
+        vec![
+            ("TOKEN".to_string(), "abc123".to_string()),
+            ("HOST".to_string(), "example.com".to_string()),
+            ("EMPTY".to_string(), "".to_string()),
+            ("COMMENTED".to_string(), "value".to_string()),
+            ("SINGLE".to_string(), r"raw \n # kept".to_string()),
+            ("DOUBLE".to_string(), "line\nbreak \"quoted\"".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_dotenv_errors() {
+    let error = parse_dotenv("TOKEN=abc\nnot a variable").unwrap_err();
+    assert_eq!(error.to_string(), "Expected NAME=value on line 2");
+
+    let error = parse_dotenv("TOKEN=\"abc").unwrap_err();
+    assert_eq!(error.to_string(), "Unterminated quote on line 1");
+
+    let error = parse_dotenv("BAD-NAME=abc").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid variable name on line 1: BAD-NAME"
+    );
+}
+
+#[test]
+fn test_load_dotenv_keeps_process_variables() {
+    std::env::set_var("DOT_HTTP_DOTENV_KEPT", "process");
+    let dir = tempfile::tempdir().unwrap();
+    let dotenv = dir.path().join(".env");
+    std::fs::write(
+        &dotenv,
+        "DOT_HTTP_DOTENV_KEPT=dotenv\nDOT_HTTP_DOTENV_LOADED=dotenv\n",
+    )
+    .unwrap();
+
+    load_dotenv(&dotenv).unwrap();
+
+    assert_eq!(std::env::var("DOT_HTTP_DOTENV_KEPT").unwrap(), "process");
+    assert_eq!(std::env::var("DOT_HTTP_DOTENV_LOADED").unwrap(), "dotenv");
+}
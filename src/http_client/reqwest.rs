@@ -1,11 +1,24 @@
 use crate::http_client::{ClientConfig, HttpClient};
-use crate::{Method, Request, Response, Result, Version};
+use crate::proxy::{self, ProxyConfig};
+use crate::retry::RetryPolicy;
+use crate::{
+    Method, Redirect, RedirectPolicy, Request, Response, Result, Timeout, TimeoutKind, Timeouts,
+    Version,
+};
+use anyhow::Context;
 use reqwest::blocking::{Client, RequestBuilder};
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, LOCATION};
+use reqwest::{StatusCode, Url};
 use std::convert::{TryFrom, TryInto};
+use std::time::Instant;
 
 pub struct ReqwestHttpClient {
     client: Client,
+    ssl_check: bool,
+    retry: RetryPolicy,
+    redirects: RedirectPolicy,
+    timeouts: Timeouts,
+    proxy: ProxyConfig,
 }
 
 impl Default for ReqwestHttpClient {
@@ -19,29 +32,231 @@ impl HttpClient for ReqwestHttpClient {
     where
         Self: Sized,
     {
-        let client = reqwest::blocking::Client::builder()
-            .danger_accept_invalid_certs(config.ssl_check)
-            .build()
-            .unwrap();
-
-        ReqwestHttpClient { client }
+        ReqwestHttpClient {
+            client: build(config.ssl_check, &config.timeouts, &config.proxy),
+            ssl_check: config.ssl_check,
+            retry: config.retry,
+            redirects: config.redirects,
+            timeouts: config.timeouts,
+            proxy: config.proxy,
+        }
     }
 
+    /// Sends the request, and again as the retry policy allows when the connection fails or the
+    /// status is one to retry
     fn execute(&self, request: &Request) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = self.send(request);
+            let delay = match &result {
+                Err(error) if is_transient(error) => self.retry.delay(attempt, None),
+                Ok(response) if self.retry.retries_status(response.status_code) => {
+                    self.retry.delay(attempt, Some(response))
+                }
+                _ => return result,
+            };
+            if attempt >= self.retry.retries {
+                return result;
+            }
+            std::thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+impl ReqwestHttpClient {
+    /// Sends the request, following the redirections its policy allows and recording each of them
+    fn send(&self, request: &Request) -> Result<Response> {
         let Request {
             method,
             target,
             headers,
             body,
+            redirects: policy,
+            timeouts,
         } = request;
-        let mut request_builder = self.client.request(method.into(), target);
-        request_builder = set_headers(headers, request_builder);
-        if let Some(body) = body {
-            request_builder = set_body(body, request_builder);
+        let policy = policy.unwrap_or(self.redirects);
+        let timeouts = timeouts.unwrap_or(self.timeouts);
+        // The connect and read timeouts are those of the client, another one is needed to change them
+        let client =
+            if (timeouts.connect, timeouts.read) == (self.timeouts.connect, self.timeouts.read) {
+                self.client.clone()
+            } else {
+                build(self.ssl_check, &timeouts, &self.proxy)
+            };
+        let start = Instant::now();
+        let mut method: reqwest::Method = method.into();
+        let mut target = target.clone();
+        let mut headers = headers.clone();
+        let mut body = body.clone();
+        let mut redirects = vec![];
+        loop {
+            let mut request_builder = client.request(method.clone(), target.as_str());
+            request_builder = set_headers(&headers, request_builder);
+            if let Some(body) = &body {
+                request_builder = set_body(body, request_builder);
+            }
+            if let Some(total) = timeouts.total {
+                let remaining = total.saturating_sub(start.elapsed());
+                if remaining.is_zero() {
+                    return Err(Timeout {
+                        kind: TimeoutKind::Total,
+                        after: total,
+                    }
+                    .into());
+                }
+                // Replaces the read timeout of the client, which still applies when it is shorter
+                let timeout = match timeouts.read {
+                    Some(read) => remaining.min(read),
+                    None => remaining,
+                };
+                request_builder = request_builder.timeout(timeout);
+            }
+            let response = request_builder
+                .send()
+                .map_err(|error| timed_out(error.into(), &timeouts, start))?;
+
+            let location = match (policy, redirect_location(&response)) {
+                (RedirectPolicy::Follow(max), Some(location)) => {
+                    if redirects.len() >= max {
+                        return Err(anyhow!(
+                            "Stopped after {} redirects, the last one to {}",
+                            max,
+                            location
+                        ));
+                    }
+                    location
+                }
+                _ => {
+                    let proxy = self.proxy.proxy_for(response.url());
+                    let mut response: Response = response
+                        .try_into()
+                        .map_err(|error| timed_out(error, &timeouts, start))?;
+                    // Reading the body starts the timeout over, the deadline is checked once it's in
+                    if let Some(total) = timeouts.total.filter(|total| start.elapsed() > *total) {
+                        return Err(Timeout {
+                            kind: TimeoutKind::Total,
+                            after: total,
+                        }
+                        .into());
+                    }
+                    response.redirects = redirects;
+                    response.proxy = proxy.as_ref().map(proxy::display);
+                    return Ok(response);
+                }
+            };
+            let url = response.url().clone();
+            let next = url
+                .join(&location)
+                .with_context(|| format!("Invalid redirect location: {}", location))?;
+            let status = response.status();
+            if status == StatusCode::SEE_OTHER
+                || (method == reqwest::Method::POST
+                    && (status == StatusCode::MOVED_PERMANENTLY || status == StatusCode::FOUND))
+            {
+                // Like browsers, the request goes on as a GET without its body
+                method = reqwest::Method::GET;
+                body = None;
+                headers.retain(|(name, _)| !is_body_header(name));
+            }
+            if !same_origin(&url, &next) {
+                headers.retain(|(name, _)| !is_credential_header(name));
+            }
+            let Headers(response_headers) = response.headers().try_into()?;
+            redirects.push(Redirect {
+                url: url.to_string(),
+                version: response.version().into(),
+                status_code: status.as_u16(),
+                status: status.to_string(),
+                location,
+                headers: response_headers,
+            });
+            target = next.to_string();
+        }
+    }
+}
+
+fn build(ssl_check: bool, timeouts: &Timeouts, proxy: &ProxyConfig) -> Client {
+    // The proxy environment variables are already part of the proxy config
+    let mut builder = Client::builder()
+        .danger_accept_invalid_certs(!ssl_check)
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(timeouts.connect)
+        .timeout(timeouts.read)
+        .no_proxy();
+    if proxy.is_set() {
+        let proxy = proxy.clone();
+        builder = builder.proxy(reqwest::Proxy::custom(move |url| proxy.proxy_for(url)));
+    }
+    builder.build().unwrap()
+}
+
+/// Adds the [`Timeout`] that was reached to the error of a request that timed out
+fn timed_out(error: anyhow::Error, timeouts: &Timeouts, start: Instant) -> anyhow::Error {
+    let timeout = match error.downcast_ref::<reqwest::Error>() {
+        Some(reqwest_error) if reqwest_error.is_timeout() => {
+            match (timeouts.total, timeouts.connect, timeouts.read) {
+                (Some(total), _, _) if start.elapsed() >= total => Timeout {
+                    kind: TimeoutKind::Total,
+                    after: total,
+                },
+                (_, Some(connect), _) if reqwest_error.is_connect() => Timeout {
+                    kind: TimeoutKind::Connect,
+                    after: connect,
+                },
+                (_, _, Some(read)) => Timeout {
+                    kind: TimeoutKind::Read,
+                    after: read,
+                },
+                _ => return error,
+            }
         }
-        let response = request_builder.send()?;
+        _ => return error,
+    };
+    error.context(timeout)
+}
+
+/// The `Location` a response redirects to, if it is a redirection
+fn redirect_location(response: &reqwest::blocking::Response) -> Option<String> {
+    match response.status() {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+fn same_origin(url: &Url, next: &Url) -> bool {
+    url.scheme() == next.scheme()
+        && url.host_str() == next.host_str()
+        && url.port_or_known_default() == next.port_or_known_default()
+}
+
+fn is_body_header(name: &str) -> bool {
+    ["content-type", "content-length", "transfer-encoding"]
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+}
+
+/// Whether a header is dropped when a redirection leads to another host
+fn is_credential_header(name: &str) -> bool {
+    ["authorization", "cookie", "proxy-authorization"]
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+}
 
-        response.try_into()
+/// Whether sending the request again may succeed, when no connection could be made. A request that
+/// timed out may have reached the server already, so it isn't sent again.
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(error) => error.is_connect(),
+        None => false,
     }
 }
 
@@ -95,6 +310,8 @@ impl TryFrom<reqwest::blocking::Response> for Response {
                 body if !body.is_empty() => Some(body),
                 _ => None,
             },
+            redirects: vec![],
+            proxy: None,
         })
     }
 }
@@ -1,8 +1,12 @@
 use crate::http_client::reqwest::ReqwestHttpClient;
 use crate::http_client::HttpClient;
-use crate::{Method, Request};
+use crate::retry::RetryPolicy;
+use crate::{
+    ClientConfig, Method, RedirectPolicy, Request, Response, Result, Timeout, TimeoutKind, Timeouts,
+};
 use httpmock::Method::POST;
 use httpmock::MockServer;
+use std::time::Duration;
 
 #[test]
 fn execute() {
@@ -33,6 +37,8 @@ fn execute() {
             ),
         ],
         body: Some(String::from(body)),
+        redirects: None,
+        timeouts: None,
     };
     let client = ReqwestHttpClient::default();
     let res = client.execute(&request).unwrap();
@@ -40,3 +46,236 @@ fn execute() {
     mock.assert();
     assert_eq!(res.status_code, 200);
 }
+
+#[test]
+fn execute_retries() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::GET).path("/busy");
+        then.status(503).header("Retry-After", "0");
+    });
+
+    let request = Request {
+        method: Method::Get,
+        target: format!("http://localhost:{}/busy", server.port()),
+        headers: vec![],
+        body: None,
+        redirects: None,
+        timeouts: None,
+    };
+    let client = ReqwestHttpClient::create(ClientConfig {
+        retry: RetryPolicy {
+            retries: 2,
+            ..RetryPolicy::default()
+        },
+        ..ClientConfig::default()
+    });
+    let res = client.execute(&request).unwrap();
+
+    mock.assert_hits(3);
+    assert_eq!(res.status_code, 503);
+
+    let request = Request {
+        target: "http://localhost:1/refused".to_string(),
+        ..request
+    };
+    let client = ReqwestHttpClient::create(ClientConfig {
+        retry: RetryPolicy {
+            retries: 1,
+            backoff: std::time::Duration::from_millis(1),
+            ..RetryPolicy::default()
+        },
+        ..ClientConfig::default()
+    });
+    assert!(client.execute(&request).is_err());
+}
+
+#[test]
+fn execute_no_retry_after_timeout() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::POST).path("/orders");
+        then.status(201).delay(Duration::from_millis(500));
+    });
+
+    let request = Request {
+        method: Method::Post,
+        target: format!("http://localhost:{}/orders", server.port()),
+        headers: vec![],
+        body: Some("{}".to_string()),
+        redirects: None,
+        timeouts: None,
+    };
+    let client = ReqwestHttpClient::create(ClientConfig {
+        retry: RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        },
+        timeouts: Timeouts {
+            read: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        },
+        ..ClientConfig::default()
+    });
+
+    assert!(client.execute(&request).is_err());
+    // Let the server take the request in before counting
+    std::thread::sleep(Duration::from_millis(600));
+    mock.assert_hits(1);
+}
+
+#[test]
+fn execute_redirects() {
+    let server = MockServer::start();
+    let old = server.mock(|when, then| {
+        when.method(POST).path("/old");
+        then.status(302)
+            .header("Location", "/moved")
+            .header("Set-Cookie", "hop=1");
+    });
+    let moved = server.mock(|when, then| {
+        when.method(httpmock::Method::GET).path("/moved");
+        then.status(307).header("Location", "/new");
+    });
+    let new = server.mock(|when, then| {
+        when.method(httpmock::Method::GET).path("/new");
+        then.status(200).body("new");
+    });
+
+    let request = Request {
+        method: Method::Post,
+        target: format!("http://localhost:{}/old", server.port()),
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: Some("old".to_string()),
+        redirects: None,
+        timeouts: None,
+    };
+    let client = ReqwestHttpClient::default();
+    let res = client.execute(&request).unwrap();
+
+    old.assert();
+    moved.assert();
+    new.assert();
+    assert_eq!(res.status_code, 200);
+    assert_eq!(res.body, Some("new".to_string()));
+    let hops: Vec<_> = res
+        .redirects
+        .iter()
+        .map(|redirect| {
+            (
+                redirect.url.as_str(),
+                redirect.status_code,
+                redirect.location.as_str(),
+            )
+        })
+        .collect();
+    let url = |path| format!("http://localhost:{}{}", server.port(), path);
+    assert_eq!(
+        hops,
+        vec![
+            (url("/old").as_str(), 302, "/moved"),
+            (url("/moved").as_str(), 307, "/new")
+        ]
+    );
+    assert!(res.redirects[0]
+        .headers
+        .contains(&("set-cookie".to_string(), "hop=1".to_string())));
+
+    let client = ReqwestHttpClient::create(ClientConfig {
+        redirects: RedirectPolicy::Follow(1),
+        ..ClientConfig::default()
+    });
+    match client.execute(&request) {
+        Err(error) => assert_eq!(
+            error.to_string(),
+            "Stopped after 1 redirects, the last one to /new"
+        ),
+        Ok(res) => panic!("Expected too many redirects, got {}", res.status),
+    }
+    let client = ReqwestHttpClient::default();
+
+    let request = Request {
+        redirects: Some(RedirectPolicy::None),
+        ..request
+    };
+    let res = client.execute(&request).unwrap();
+    assert_eq!(res.status_code, 302);
+    assert!(res.redirects.is_empty());
+}
+
+#[test]
+fn execute_timeouts() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::GET).path("/slow");
+        then.status(200).delay(Duration::from_millis(500));
+    });
+
+    let request = Request {
+        method: Method::Get,
+        target: format!("http://localhost:{}/slow", server.port()),
+        headers: vec![],
+        body: None,
+        redirects: None,
+        timeouts: None,
+    };
+    let timeouts = Timeouts {
+        read: Some(Duration::from_millis(100)),
+        ..Timeouts::default()
+    };
+    let client = ReqwestHttpClient::create(ClientConfig {
+        timeouts,
+        ..ClientConfig::default()
+    });
+    let timeout = |result: Result<Response>| match result {
+        Err(error) => error.downcast_ref::<Timeout>().cloned(),
+        Ok(response) => panic!("Expected a timeout, got {}", response.status),
+    };
+
+    assert_eq!(
+        timeout(client.execute(&request)),
+        Some(Timeout {
+            kind: TimeoutKind::Read,
+            after: Duration::from_millis(100),
+        })
+    );
+
+    let request = Request {
+        timeouts: Some(Timeouts {
+            read: None,
+            total: Some(Duration::from_millis(200)),
+            ..timeouts
+        }),
+        ..request
+    };
+    assert_eq!(
+        timeout(client.execute(&request)),
+        Some(Timeout {
+            kind: TimeoutKind::Total,
+            after: Duration::from_millis(200),
+        })
+    );
+
+    let request = Request {
+        timeouts: Some(Timeouts {
+            total: Some(Duration::from_secs(10)),
+            ..timeouts
+        }),
+        ..request
+    };
+    assert_eq!(
+        timeout(client.execute(&request)),
+        Some(Timeout {
+            kind: TimeoutKind::Read,
+            after: Duration::from_millis(100),
+        })
+    );
+
+    let request = Request {
+        timeouts: Some(Timeouts::default()),
+        ..request
+    };
+    assert_eq!(client.execute(&request).unwrap().status_code, 200);
+    mock.assert_hits(4);
+}
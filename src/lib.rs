@@ -2,47 +2,166 @@
 extern crate anyhow;
 #[macro_use]
 extern crate pest_derive;
-#[macro_use]
 extern crate pest;
 
+use crate::cookies::{CookieJar, CookieJarFile};
+use crate::data::Data;
 use crate::http_client::reqwest::ReqwestHttpClient;
 use crate::http_client::HttpClient;
 use crate::output::Outputter;
 use crate::parser::{parse, Header};
-use crate::script_engine::{create_script_engine, ScriptEngine};
+use crate::retry::Polling;
+use crate::script_engine::{create_engine, Control, ScriptEngine};
+use crate::secrets::Secrets;
+use crate::snapshot::{Namespace, SnapshotFile};
 use anyhow::Context;
 use std::borrow::BorrowMut;
 use std::fs::read_to_string;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 
+mod cookies;
+mod data;
+mod environment;
 mod http_client;
 pub mod output;
 mod parser;
+mod proxy;
+mod retry;
 mod script_engine;
+mod secrets;
+mod snapshot;
+mod timeout;
+mod variables;
+
+pub use crate::data::Iteration;
+pub use crate::proxy::ProxyConfig;
+pub use crate::retry::{parse_duration, RetryPolicy};
+pub use crate::script_engine::{Engine, Limits};
+pub use crate::snapshot::{Persist, SnapshotScope};
+pub use crate::timeout::{Timeout, TimeoutKind, Timeouts};
+pub use crate::variables::{Source, Variable, Variables};
 
 pub type Result<T> = anyhow::Result<T>;
 
+/// How many times `client.goto` may jump within a run, so that requests going to each other end
+const MAX_JUMPS: usize = 1000;
+
 pub struct ClientConfig {
     pub ssl_check: bool,
+    /// How requests failing with a connection error or a status like 429 are sent again
+    pub retry: RetryPolicy,
+    /// Whether redirections are followed, requests may override it with `# @no-follow` or
+    /// `# @max-redirects`
+    pub redirects: RedirectPolicy,
+    /// How long to wait on a server, requests may override it with `# @timeout`
+    pub timeouts: Timeouts,
+    /// The proxy requests are sent through, completed with the `$proxy` section of the
+    /// environment and the `HTTP_PROXY` like environment variables
+    pub proxy: ProxyConfig,
+    /// The file the cookies of each environment are kept in between runs, when they are
+    pub cookie_jar: Option<PathBuf>,
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
-        Self { ssl_check: true }
+        Self {
+            ssl_check: true,
+            retry: RetryPolicy::default(),
+            redirects: RedirectPolicy::default(),
+            timeouts: Timeouts::default(),
+            proxy: ProxyConfig::default(),
+            cookie_jar: None,
+        }
     }
 }
 
 impl ClientConfig {
     pub fn new(ssl_check: bool) -> Self {
-        Self { ssl_check }
+        Self {
+            ssl_check,
+            ..Self::default()
+        }
+    }
+}
+
+/// How the client goes on with a response redirecting the request elsewhere
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectPolicy {
+    /// The redirection is the response
+    None,
+    /// Redirections are followed, failing the request after this many
+    Follow(usize),
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::Follow(10)
+    }
+}
+
+#[derive(Default)]
+pub struct ScriptConfig {
+    pub engine: Engine,
+    pub limits: Limits,
+    /// A script evaluated after `init.js`, used instead of the `http-client.js` found next to
+    /// the environment file, or of `http-client.rhai` when it is a `.rhai` file
+    pub prelude: Option<PathBuf>,
+    /// A dotenv file loaded into the process environment, which scripts read as `$processEnv`
+    pub dotenv: Option<PathBuf>,
+    /// Whether global variables are shared by the whole environment or kept per script file
+    pub snapshot_scope: SnapshotScope,
+    /// Whether global variables are written after each request or once the whole run succeeds
+    pub persist: Persist,
+    /// Variables given on the command line, they take precedence over the environment and the
+    /// snapshot and are never persisted
+    pub overrides: serde_json::Map<String, serde_json::Value>,
+    /// A CSV or JSON data file, the requests are run once for each of its rows
+    pub data: Option<PathBuf>,
+    /// Whether the rows of a data file after a failing one are still run, the failures are then
+    /// reported once every row ran
+    pub keep_going: bool,
+}
+
+impl ScriptConfig {
+    /// The prelude of an engine, if there is one
+    fn prelude(&self, engine: Engine, env_dir: &Path) -> Option<PathBuf> {
+        let rhai = engine == Engine::Rhai;
+        match &self.prelude {
+            Some(path) if (path.extension() == Some("rhai".as_ref())) == rhai => Some(path.clone()),
+            _ => {
+                let name = if rhai {
+                    "http-client.rhai"
+                } else {
+                    "http-client.js"
+                };
+                Some(env_dir.join(name)).filter(|path| path.is_file())
+            }
+        }
     }
 }
 
 pub struct Runtime<'a> {
     engine: Box<dyn ScriptEngine>,
-    snapshot_file: PathBuf,
+    engine_kind: Engine,
+    script_config: ScriptConfig,
+    env: String,
+    env_file: String,
+    env_dir: PathBuf,
+    snapshot: SnapshotFile,
+    namespace: Namespace,
+    /// The global variables of the namespace as last read from or written to the snapshot file
+    persisted: String,
     outputter: &'a mut dyn Outputter,
     client: Box<dyn HttpClient>,
+    secrets: Secrets,
+    /// The timeouts of the client, which `# @timeout` directives change for a request
+    timeouts: Timeouts,
+    /// The cookies received during the run, shared by its requests
+    cookies: CookieJar,
+    cookie_jar: Option<CookieJarFile>,
+    /// The status a script exited the last run with, through `client.exit`
+    exit_status: Option<i32>,
 }
 
 impl<'a> Runtime<'a> {
@@ -51,115 +170,666 @@ impl<'a> Runtime<'a> {
         snapshot_file: &Path,
         env_file: &Path,
         outputter: &'a mut dyn Outputter,
-        config: ClientConfig,
+        mut config: ClientConfig,
+        script_config: ScriptConfig,
     ) -> Result<Runtime<'a>> {
-        let env_file = match read_to_string(env_file) {
-            Ok(script) => Ok(script),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                std::fs::write(env_file, "{}").unwrap();
-                Ok("{}".to_string())
-            }
-            Err(e) => Err(e),
-        }?;
+        let env_dir = env_file.parent().map(Path::to_path_buf).unwrap_or_default();
+        if let Some(dotenv) = &script_config.dotenv {
+            environment::load_dotenv(dotenv)?;
+        }
+        let environment::Environment {
+            script: env_file,
+            secrets,
+            proxy,
+        } = environment::load(env_file, env)?;
+        let secrets = Secrets::from_section(secrets.as_ref(), &env_dir)?;
+        if let Some(proxy) = proxy {
+            let proxy = ProxyConfig::from_section(&proxy)?;
+            config.proxy = config.proxy.or(proxy);
+        }
+        config.proxy = config.proxy.or(ProxyConfig::from_env());
+        config.proxy.validate()?;
 
-        let snapshot = match read_to_string(snapshot_file) {
-            Ok(script) => Ok(script),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok("{}".to_string()),
-            Err(e) => Err(e),
-        }?;
+        let snapshot = SnapshotFile::load(snapshot_file, env)?;
+        let namespace = Namespace {
+            env: env.to_string(),
+            file: None,
+        };
+        let persisted = snapshot.variables(&namespace);
 
-        let engine = create_script_engine(&env_file, env, &snapshot);
+        let engine = start_engine(
+            script_config.engine,
+            &env_file,
+            env,
+            &persisted,
+            &script_config,
+            &env_dir,
+        )?;
+        let cookie_jar = config
+            .cookie_jar
+            .as_ref()
+            .map(|path| CookieJarFile::new(path, env));
+        let cookies = match &cookie_jar {
+            Some(cookie_jar) => cookie_jar.load()?,
+            None => CookieJar::default(),
+        };
+        let timeouts = config.timeouts;
         let client = Box::new(ReqwestHttpClient::create(config));
 
         Ok(Runtime {
             outputter,
-            snapshot_file: PathBuf::from(snapshot_file),
+            snapshot,
+            namespace,
+            persisted,
             engine,
+            engine_kind: script_config.engine,
+            script_config,
+            env: env.to_string(),
+            env_file,
+            env_dir,
             client,
+            secrets,
+            timeouts,
+            cookies,
+            cookie_jar,
+            exit_status: None,
         })
     }
 
+    /// Switches to the engine of the file's script language and to the file's snapshot namespace,
+    /// carrying the global variables over when the namespace stays the same
+    fn switch_engine(&mut self, engine: Engine, namespace: Namespace) -> Result<()> {
+        if engine == self.engine_kind && namespace == self.namespace {
+            return Ok(());
+        }
+        let variables = if namespace == self.namespace {
+            self.engine
+                .snapshot()
+                .with_context(|| "Error creating snapshot")?
+        } else {
+            self.persisted = self.snapshot.variables(&namespace);
+            self.persisted.clone()
+        };
+        self.engine = start_engine(
+            engine,
+            &self.env_file,
+            &self.env,
+            &variables,
+            &self.script_config,
+            &self.env_dir,
+        )?;
+        self.engine_kind = engine;
+        self.namespace = namespace;
+        Ok(())
+    }
+
+    /// The status a script passed to `client.exit` during the last run, if one did
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
     pub fn execute(&mut self, script_file: &Path, offset: usize, all: bool) -> Result<()> {
-        let file = read_to_string(&script_file)
+        let result = self.execute_file(script_file, offset, all);
+        let synced = self
+            .snapshot
+            .sync()
+            .with_context(|| "Error writing snapshot");
+        result.and(synced)
+    }
+
+    fn execute_file(&mut self, script_file: &Path, offset: usize, all: bool) -> Result<()> {
+        let file = read_to_string(script_file)
             .with_context(|| format!("Failed opening script file: {:?}", script_file))?;
         let file = &mut parse(script_file.to_path_buf(), file.as_str())
             .with_context(|| format!("Failed parsing file: {:?}", script_file))?;
 
-        let request_scripts = file.request_scripts(offset, all);
+        let namespace = Namespace::new(
+            self.script_config.snapshot_scope,
+            &self.env,
+            self.snapshot.path(),
+            script_file,
+        );
+        self.switch_engine(
+            select_engine(script_file, file, self.script_config.engine)?,
+            namespace,
+        )?;
 
-        let engine = &mut *self.engine;
-        let outputter = self.outputter.borrow_mut();
-        let client = &self.client;
-
-        for request_script in request_scripts {
-            let request = process(engine, &request_script.request)
-                .with_context(|| format!("Failed processing request found on line {}", offset))?;
-            outputter
-                .request(&request)
-                .with_context(|| format!("Failed outputting request found on line {}", offset))?;
-
-            let response = client
-                .execute(&request)
-                .with_context(|| format!("Error executing request found on line {}", offset))?;
-            outputter.response(&response).with_context(|| {
-                format!(
-                    "Error outputting response for request found on line {}",
-                    offset
-                )
-            })?;
+        let request_scripts: Vec<_> = file.request_scripts.iter().collect();
+        let selected = file.request_scripts(offset, all).collect::<Vec<_>>();
+        let start = selected
+            .first()
+            .and_then(|first| {
+                request_scripts
+                    .iter()
+                    .position(|request_script| std::ptr::eq(*request_script, *first))
+            })
+            .unwrap_or(0);
+        let selected = start..start + selected.len();
+        let script_dir = script_file.parent().unwrap_or_else(|| Path::new(""));
+
+        self.exit_status = None;
+        let mut failures = vec![];
+        match &self.script_config.data {
+            None => {
+                self.engine
+                    .reset_run()
+                    .with_context(|| "Error resetting the script engine")?;
+                self.exit_status = self.execute_requests(
+                    &request_scripts,
+                    selected,
+                    script_dir,
+                    offset,
+                    &serde_json::Map::new(),
+                    &mut failures,
+                )?;
+            }
+            Some(path) => {
+                let data = Data::load(path)?;
+                for (iteration, row) in data.iterations() {
+                    self.outputter.iteration(&iteration)?;
+                    self.engine
+                        .reset_run()
+                        .with_context(|| "Error resetting the script engine")?;
+                    let result = self
+                        .execute_requests(
+                            &request_scripts,
+                            selected.clone(),
+                            script_dir,
+                            offset,
+                            row,
+                            &mut failures,
+                        )
+                        .with_context(|| format!("Failed on {}", iteration));
+                    self.exit_status = self.fail(result, &mut failures)?;
+                    if self.exit_status.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.script_config.persist == Persist::Success && failures.is_empty() {
+            self.persist()?;
+        }
+        match failures.len() {
+            0 => Ok(()),
+            count => Err(anyhow!(
+                "{} failed:\n{}",
+                if count == 1 {
+                    "1 row".to_string()
+                } else {
+                    format!("{} rows", count)
+                },
+                failures
+                    .iter()
+                    .map(|failure| format!("{:#}", failure))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            )),
+        }
+    }
+
+    /// Runs the selected requests, each of them once for every row of its `# @data` file if it
+    /// has one, following the `client.goto` of their scripts. Returns the status a script exited
+    /// the run with.
+    fn execute_requests(
+        &mut self,
+        request_scripts: &[&parser::RequestScript],
+        selected: Range<usize>,
+        script_dir: &Path,
+        offset: usize,
+        variables: &serde_json::Map<String, serde_json::Value>,
+        failures: &mut Vec<anyhow::Error>,
+    ) -> Result<Option<i32>> {
+        let Range { mut start, mut end } = selected;
+        let mut jumps = 0;
+        while start < end {
+            let request_script = request_scripts[start];
+            let control = match request_script.directive("data") {
+                None => self.execute_request(request_script, offset, variables)?,
+                Some(parser::Directive { value: path, .. }) => {
+                    let data = Data::load(&script_dir.join(path))?;
+                    let mut control = Control::Continue;
+                    for (iteration, row) in data.iterations() {
+                        self.outputter.iteration(&iteration)?;
+                        let mut variables = variables.clone();
+                        variables.extend(
+                            row.iter()
+                                .map(|(name, value)| (name.clone(), value.clone())),
+                        );
+                        let result = self
+                            .execute_request(request_script, offset, &variables)
+                            .with_context(|| format!("Failed on {}", iteration));
+                        control = self.fail(result, failures)?;
+                        if let Control::Exit(_) | Control::Goto(_) = control {
+                            break;
+                        }
+                    }
+                    control
+                }
+            };
+            match control {
+                Control::Continue | Control::Skip => start += 1,
+                Control::Exit(status) => return Ok(Some(status)),
+                Control::Goto(name) => {
+                    jumps += 1;
+                    if jumps > MAX_JUMPS {
+                        return Err(anyhow!(
+                            "Stopped after {} jumps with client.goto, the requests seem to loop",
+                            MAX_JUMPS
+                        ));
+                    }
+                    start = request_scripts
+                        .iter()
+                        .position(|request_script| {
+                            request_script
+                                .directive("name")
+                                .is_some_and(|directive| directive.value == name)
+                        })
+                        .ok_or_else(|| anyhow!("No request named {} to go to", name))?;
+                    end = end.max(start + 1);
+                }
+            }
+        }
+        Ok(None)
+    }
 
-            if let Some(parser::Handler { script, selection }) = &request_script.handler {
-                engine
-                    .handle(
+    fn execute_request(
+        &mut self,
+        request_script: &parser::RequestScript,
+        offset: usize,
+        variables: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Control> {
+        let polling = match request_script.directive("retry") {
+            Some(directive) => Some((
+                Polling::parse(&directive.value).with_context(|| {
+                    format!("Invalid retry directive at {}", directive.selection)
+                })?,
+                directive.selection.clone(),
+            )),
+            None => None,
+        };
+        let mut attempt = 1;
+        let response = loop {
+            // Every attempt starts from a clean request scope, like a request sent anew would
+            if attempt > 1 {
+                self.engine.reset()?;
+            }
+            if !variables.is_empty() {
+                self.engine
+                    .declare_variables(variables)
+                    .with_context(|| "Error declaring the variables of the data row")?;
+            }
+            let control = self.pre_request(request_script, offset)?;
+            if control != Control::Continue {
+                self.complete()?;
+                return Ok(control);
+            }
+            let response = self.send(request_script, offset)?;
+            let (polling, selection) = match &polling {
+                Some(polling) => polling,
+                None => break response,
+            };
+            let done = match &polling.until {
+                Some(until) => self
+                    .engine
+                    .condition(
                         &script_engine::Script {
                             selection: selection.clone(),
-                            src: script.as_str(),
+                            src: &script_engine::expand_process_env(until.clone()),
                         },
                         &response,
                     )
                     .with_context(|| {
-                        format!(
-                            "Error handling response for request found on line {}",
-                            offset
-                        )
-                    })?;
+                        format!("Error evaluating the retry condition at {}", selection)
+                    })?,
+                None => (200..300).contains(&response.status_code),
+            };
+            if done {
+                break response;
+            }
+            if attempt >= polling.max {
+                return Err(anyhow!(
+                    "The retry condition at {} still failed after {} attempts",
+                    selection,
+                    polling.max
+                ));
+            }
+            std::thread::sleep(polling.interval);
+            attempt += 1;
+        };
+
+        let engine = &mut *self.engine;
+        let mut control = Control::Continue;
+        if let Some(handler) = &request_script.handler {
+            let (script, selection) = load_handler(handler)?;
+            engine.set_cookies(&self.cookies.to_script())?;
+            control = engine
+                .handle(
+                    &script_engine::Script {
+                        selection: selection.clone(),
+                        src: script.as_str(),
+                    },
+                    &response,
+                )
+                .with_context(|| {
+                    format!(
+                        "Error handling response at {} for request found on line {}",
+                        selection, offset
+                    )
+                })?;
+            self.cookies.set_script(&engine.cookies()?)?;
+        }
+
+        if let Some(parser::Directive { value: name, .. }) = request_script.directive("name") {
+            engine.declare_response(name, &response).with_context(|| {
+                format!("Error declaring the response of the request named {}", name)
+            })?;
+        }
+
+        self.complete()?;
+        Ok(control)
+    }
+
+    /// Runs the pre-request script, returning what it asked the run to do next
+    fn pre_request(
+        &mut self,
+        request_script: &parser::RequestScript,
+        offset: usize,
+    ) -> Result<Control> {
+        let handler = match &request_script.pre_request {
+            Some(handler) => handler,
+            None => return Ok(Control::Continue),
+        };
+        let (script, selection) = load_handler(handler)?;
+        self.engine.set_cookies(&self.cookies.to_script())?;
+        let control = self
+            .engine
+            .pre_request(&script_engine::Script {
+                selection: selection.clone(),
+                src: script.as_str(),
+            })
+            .with_context(|| {
+                format!(
+                    "Error running the pre-request script at {} for request found on line {}",
+                    selection, offset
+                )
+            })?;
+        self.cookies.set_script(&self.engine.cookies()?)?;
+        Ok(control)
+    }
+
+    /// Clears the request scope once a request is done with, persists the global variables when
+    /// they are persisted after each request and saves the cookie jar
+    fn complete(&mut self) -> Result<()> {
+        self.engine
+            .reset()
+            .with_context(|| "Error resetting the script engine")?;
+        if self.script_config.persist == Persist::Request {
+            self.persist()?;
+        }
+        if let Some(cookie_jar) = &self.cookie_jar {
+            cookie_jar.save(&self.cookies)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the request and outputs both the request and its response
+    fn send(&mut self, request_script: &parser::RequestScript, offset: usize) -> Result<Response> {
+        let engine = &mut *self.engine;
+        let outputter = self.outputter.borrow_mut();
+        let secrets = &mut self.secrets;
+
+        let mut request = process(engine, secrets, &request_script.request)
+            .with_context(|| format!("Failed processing request found on line {}", offset))?;
+        request.redirects = redirect_policy(request_script)?;
+        if let Some(directive) = request_script.directive("timeout") {
+            request.timeouts = Some(self.timeouts.apply(&directive.value).with_context(|| {
+                format!("Invalid timeout directive at {}", directive.selection)
+            })?);
+        }
+        // Requests with a `# @no-cookie-jar` directive neither send nor receive the jar's cookies
+        let url = match request_script.directive("no-cookie-jar") {
+            None => reqwest::Url::parse(&request.target).ok(),
+            Some(_) => None,
+        };
+        if let Some(url) = &url {
+            let has_cookie = request
+                .headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("cookie"));
+            if let (false, Some(cookie)) = (has_cookie, self.cookies.header(url)) {
+                request.headers.push(("Cookie".to_string(), cookie));
+            }
+        }
+        for secret in secrets.values() {
+            outputter.mask(secret);
+        }
+        outputter
+            .request(&request)
+            .with_context(|| format!("Failed outputting request found on line {}", offset))?;
+
+        let response = self
+            .client
+            .execute(&request)
+            .with_context(|| format!("Error executing request found on line {}", offset))?;
+        if let Some(mut url) = url {
+            // Cookies are also set by the redirections, each for the URL it was received from
+            for redirect in &response.redirects {
+                url = reqwest::Url::parse(&redirect.url)?;
+                self.cookies.store(&url, &redirect.headers);
+                url = url.join(&redirect.location)?;
             }
+            self.cookies.store(&url, &response.headers);
+        }
+        outputter.response(&response).with_context(|| {
+            format!(
+                "Error outputting response for request found on line {}",
+                offset
+            )
+        })?;
+        Ok(response)
+    }
 
-            engine.reset().unwrap();
+    /// Records the failure of a data row when running with `keep_going`, fails the run otherwise
+    fn fail<T: Default>(
+        &mut self,
+        result: Result<T>,
+        failures: &mut Vec<anyhow::Error>,
+    ) -> Result<T> {
+        match result {
+            Err(error) if self.script_config.keep_going => {
+                self.engine
+                    .reset()
+                    .with_context(|| "Error resetting the script engine")?;
+                failures.push(error);
+                Ok(T::default())
+            }
+            result => result,
         }
-        let snapshot = engine
+    }
+
+    /// Writes the global variables the engine changed since they were last persisted
+    fn persist(&mut self) -> Result<()> {
+        let variables = self
+            .engine
             .snapshot()
             .with_context(|| "Error creating snapshot")?;
-
-        std::fs::write(self.snapshot_file.as_path(), snapshot)
+        let variables = scrub_snapshot(&self.secrets, variables)?;
+        self.snapshot
+            .persist(&self.namespace, &self.persisted, &variables)
             .with_context(|| "Error writing snapshot")?;
-
+        self.persisted = variables;
         Ok(())
     }
 }
 
-fn process_header(engine: &mut dyn ScriptEngine, header: &Header) -> Result<(String, String)> {
+/// Drops the snapshot variables holding a secret, leaving the snapshot untouched when none were
+/// resolved
+fn scrub_snapshot(secrets: &Secrets, snapshot: String) -> Result<String> {
+    if secrets.values().next().is_none() {
+        return Ok(snapshot);
+    }
+    let mut variables: serde_json::Value =
+        serde_json::from_str(&snapshot).with_context(|| "Error reading snapshot")?;
+    secrets.scrub(&mut variables);
+    Ok(variables.to_string())
+}
+
+/// Runs the inline scripts of a value, except for the `{{$secret name}}` ones which are resolved
+/// afterwards so that secrets never reach the script engine
+fn process_value(
+    engine: &mut dyn ScriptEngine,
+    secrets: &mut Secrets,
+    value: &parser::Value,
+) -> Result<String> {
+    let mut value: script_engine::Value<script_engine::Unprocessed> = value.into();
+    let mut secret_scripts = vec![];
+    if let script_engine::Unprocessed::WithInline { inline_scripts, .. } = &mut value.state {
+        inline_scripts.retain(
+            |inline_script| match secrets::secret_name(&inline_script.script) {
+                Some(name) => {
+                    secret_scripts.push((inline_script.placeholder.clone(), name.to_string()));
+                    false
+                }
+                None => true,
+            },
+        );
+    }
+    let mut processed = engine.process(value)?.state.value;
+    for (placeholder, name) in secret_scripts {
+        processed = processed.replacen(placeholder.as_str(), secrets.resolve(&name)?, 1);
+    }
+    Ok(processed)
+}
+
+fn process_header(
+    engine: &mut dyn ScriptEngine,
+    secrets: &mut Secrets,
+    header: &Header,
+) -> Result<(String, String)> {
     let parser::Header {
         field_name,
         field_value,
         ..
     } = header;
-    engine
-        .process(field_value.into())
-        .map(|value| (field_name.clone(), value.state.value))
+    process_value(engine, secrets, field_value).map(|value| (field_name.clone(), value))
 }
 
 fn process_headers(
     engine: &mut dyn ScriptEngine,
+    secrets: &mut Secrets,
     headers: &[Header],
 ) -> Result<Vec<(String, String)>> {
     headers
         .iter()
-        .map(|header| process_header(engine, header))
+        .map(|header| process_header(engine, secrets, header))
         .collect()
 }
 
-fn process(engine: &mut dyn ScriptEngine, request: &parser::Request) -> Result<Request> {
+/// Creates an engine and evaluates its prelude, see [`ScriptConfig::prelude`]
+fn start_engine(
+    engine: Engine,
+    env_script: &str,
+    env: &str,
+    snapshot: &str,
+    script_config: &ScriptConfig,
+    env_dir: &Path,
+) -> Result<Box<dyn ScriptEngine>> {
+    let overrides = serde_json::Value::Object(script_config.overrides.clone()).to_string();
+    let mut script_engine = create_engine(
+        engine,
+        env_script,
+        env,
+        snapshot,
+        &overrides,
+        &script_config.limits,
+    )
+    .with_context(|| format!("Failed creating the {} script engine", engine))?;
+    if let Some(path) = script_config.prelude(engine, env_dir) {
+        let (script, selection) = load_script(&path)?;
+        script_engine
+            .load_prelude(&script_engine::Script {
+                selection,
+                src: script.as_str(),
+            })
+            .with_context(|| format!("Error running the prelude {:?}", path))?;
+    }
+    Ok(script_engine)
+}
+
+/// Reads the script of a handler kept in its own file, the selection then covers that file
+fn load_handler(handler: &parser::Handler) -> Result<(String, parser::Selection)> {
+    let (script, selection) = match &handler.path {
+        Some(path) => load_script(path)?,
+        None => (handler.script.clone(), handler.selection.clone()),
+    };
+    Ok((script_engine::expand_process_env(script), selection))
+}
+
+fn load_script(path: &Path) -> Result<(String, parser::Selection)> {
+    let script =
+        read_to_string(path).with_context(|| format!("Failed opening script file: {:?}", path))?;
+    let selection = parser::Selection {
+        filename: path.to_path_buf(),
+        start: parser::Position { line: 1, col: 1 },
+        end: parser::Position {
+            line: script.lines().count().max(1),
+            col: 1,
+        },
+    };
+    Ok((script, selection))
+}
+
+/// Scripts use the configured engine, unless the file picks its language with a
+/// `# @language rhai` (or `javascript`) directive on its first request, or is named `*.rhai.http`
+fn select_engine(script_file: &Path, file: &parser::File, default: Engine) -> Result<Engine> {
+    let javascript = match default {
+        Engine::Rhai => Engine::default(),
+        engine => engine,
+    };
+    let language = file
+        .request_scripts
+        .first()
+        .and_then(|request_script| request_script.directive("language"));
+    let extension = script_file
+        .file_stem()
+        .map(Path::new)
+        .and_then(Path::extension);
+    match language.map(|directive| directive.value.as_str()) {
+        Some("rhai") => Ok(Engine::Rhai),
+        Some("javascript") | Some("js") => Ok(javascript),
+        Some(language) => Err(anyhow!("Unknown script language: {}", language)),
+        None if extension == Some("rhai".as_ref()) => Ok(Engine::Rhai),
+        None => Ok(default),
+    }
+}
+
+/// The redirect policy a `# @no-follow` or `# @max-redirects 3` directive sets for a request
+fn redirect_policy(request_script: &parser::RequestScript) -> Result<Option<RedirectPolicy>> {
+    if request_script.directive("no-follow").is_some() {
+        return Ok(Some(RedirectPolicy::None));
+    }
+    match request_script.directive("max-redirects") {
+        Some(directive) => {
+            let max = directive.value.trim().parse().map_err(|_| {
+                anyhow!(
+                    "Invalid max-redirects directive at {}, expected a number, found: {}",
+                    directive.selection,
+                    directive.value
+                )
+            })?;
+            Ok(Some(RedirectPolicy::Follow(max)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn process(
+    engine: &mut dyn ScriptEngine,
+    secrets: &mut Secrets,
+    request: &parser::Request,
+) -> Result<Request> {
     let parser::Request {
         method,
         target,
@@ -167,19 +837,18 @@ fn process(engine: &mut dyn ScriptEngine, request: &parser::Request) -> Result<R
         body,
         ..
     } = request;
-    let headers = process_headers(engine, headers)?;
+    let headers = process_headers(engine, secrets, headers)?;
     Ok(Request {
         method: method.into(),
-        target: engine
-            .process(target.into())
-            .with_context(|| format!("Failed processing: {}", target))?
-            .state
-            .value,
+        target: process_value(engine, secrets, target)
+            .with_context(|| format!("Failed processing: {}", target))?,
         headers,
         body: match body {
             None => None,
-            Some(body) => Some(engine.process(body.into())?.state.value),
+            Some(body) => Some(process_value(engine, secrets, body)?),
         },
+        redirects: None,
+        timeouts: None,
     })
 }
 
@@ -244,6 +913,10 @@ pub struct Request {
     pub target: String,
     pub headers: Vec<(String, String)>,
     pub body: Option<String>,
+    /// Overrides the redirect policy of the client for this request
+    pub redirects: Option<RedirectPolicy>,
+    /// Overrides the timeouts of the client for this request
+    pub timeouts: Option<Timeouts>,
 }
 
 pub enum Method {
@@ -268,4 +941,20 @@ pub struct Response {
     pub status: String,
     pub headers: Vec<(String, String)>,
     pub body: Option<String>,
+    /// The redirections followed on the way to this response, in order
+    pub redirects: Vec<Redirect>,
+    /// The proxy the request was sent through, its password masked
+    pub proxy: Option<String>,
+}
+
+/// A response redirecting the request elsewhere, which the client followed
+pub struct Redirect {
+    /// Where the request that got this response was sent
+    pub url: String,
+    pub version: Version,
+    pub status_code: u16,
+    pub status: String,
+    /// The `Location` header, as sent by the server
+    pub location: String,
+    pub headers: Vec<(String, String)>,
 }
@@ -3,7 +3,7 @@ pub mod print;
 #[cfg(test)]
 mod tests;
 
-use crate::{Method, Request, Response, Result, Version};
+use crate::{Iteration, Method, Request, Response, Result, Version};
 use std::fmt;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -61,6 +61,12 @@ fn prettify_response_body(body: &str) -> String {
 pub trait Outputter {
     fn response(&mut self, response: &Response) -> Result<()>;
     fn request(&mut self, request: &Request) -> Result<()>;
+    /// Called with every secret resolved for a request, before that request is output
+    fn mask(&mut self, _secret: &str) {}
+    /// Called before the requests are run with a row of a data file
+    fn iteration(&mut self, _iteration: &Iteration) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl fmt::Display for Version {
@@ -1,11 +1,14 @@
 use crate::output::{prettify_response_body, FormatItem, Outputter};
-use crate::{Request, Response, Result};
+use crate::secrets::mask;
+use crate::{Iteration, Request, Response, Result};
 use std::io::Write;
 
 pub struct FormattedOutputter<'a, W: Write> {
     writer: &'a mut W,
     request_format: Vec<FormatItem>,
     response_format: Vec<FormatItem>,
+    secrets: Vec<String>,
+    verbose: bool,
 }
 
 impl<'a, W: Write> FormattedOutputter<'a, W> {
@@ -13,13 +16,22 @@ impl<'a, W: Write> FormattedOutputter<'a, W> {
         writer: &mut W,
         request_format: Vec<FormatItem>,
         response_format: Vec<FormatItem>,
-    ) -> FormattedOutputter<W> {
+    ) -> FormattedOutputter<'_, W> {
         FormattedOutputter {
             writer,
             request_format,
             response_format,
+            secrets: vec![],
+            verbose: false,
         }
     }
+
+    /// Also writes how requests were sent, like the proxy they went through, on lines starting
+    /// with `* `
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
 }
 
 fn format_headers(headers: &[(String, String)]) -> String {
@@ -36,27 +48,58 @@ fn format_body(body: &Option<String>) -> String {
     }
 }
 
+impl<'a, W: Write> FormattedOutputter<'a, W> {
+    fn write_response(
+        &mut self,
+        first_line: &str,
+        headers: &[(String, String)],
+        body: &Option<String>,
+    ) -> Result<()> {
+        for format_item in &self.response_format {
+            let to_write = match format_item {
+                FormatItem::FirstLine => first_line.to_string(),
+                FormatItem::Headers => format_headers(headers),
+                FormatItem::Body => format_body(body),
+                FormatItem::Chars(s) => s.clone(),
+            };
+
+            let to_write = mask(&to_write, self.secrets.iter().map(String::as_str));
+            self.writer.write_all(to_write.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
 impl<'a, W: Write> Outputter for FormattedOutputter<'a, W> {
+    /// Writes the redirections followed, their first line pointing to where they led, then the
+    /// response they led to
     fn response(&mut self, response: &Response) -> Result<()> {
         let Response {
             headers,
             version,
             status,
             body,
+            redirects,
+            proxy,
             ..
         } = response;
 
-        for format_item in &self.response_format {
-            let to_write = match format_item {
-                FormatItem::FirstLine => format!("{} {}", version, status),
-                FormatItem::Headers => format_headers(headers),
-                FormatItem::Body => format_body(body),
-                FormatItem::Chars(s) => s.clone(),
+        if self.verbose {
+            let line = match proxy {
+                Some(proxy) => format!("* Proxy: {}\n", proxy),
+                None => "* No proxy\n".to_string(),
             };
-
-            self.writer.write_all(to_write.as_bytes())?;
+            let line = mask(&line, self.secrets.iter().map(String::as_str));
+            self.writer.write_all(line.as_bytes())?;
         }
-        Ok(())
+        for redirect in redirects {
+            let first_line = format!(
+                "{} {} -> {}",
+                redirect.version, redirect.status, redirect.location
+            );
+            self.write_response(&first_line, &redirect.headers, &None)?;
+        }
+        self.write_response(&format!("{} {}", version, status), headers, body)
     }
     fn request(&mut self, request: &Request) -> Result<()> {
         let Request {
@@ -75,8 +118,20 @@ impl<'a, W: Write> Outputter for FormattedOutputter<'a, W> {
                 FormatItem::Chars(s) => s.clone(),
             };
 
+            let to_write = mask(&to_write, self.secrets.iter().map(String::as_str));
             self.writer.write_all(to_write.as_bytes())?;
         }
         Ok(())
     }
+
+    fn iteration(&mut self, iteration: &Iteration) -> Result<()> {
+        writeln!(self.writer, "[{}]", iteration)?;
+        Ok(())
+    }
+
+    fn mask(&mut self, secret: &str) {
+        if !secret.is_empty() && !self.secrets.iter().any(|known| known == secret) {
+            self.secrets.push(secret.to_string());
+        }
+    }
 }
@@ -2,7 +2,7 @@ use crate::{
     output::{
         parse_format, prettify_response_body, print::FormattedOutputter, FormatItem, Outputter,
     },
-    Method, Request, Response, Version,
+    Method, Redirect, Request, Response, Version,
 };
 
 #[test]
@@ -58,6 +58,8 @@ fn test_format_request() {
         target: "localhost:8080".to_string(),
         headers: vec![("Content-Type".to_string(), "text/json".to_string())],
         body: Some("{\"req\":\"great\"}".to_string()),
+        redirects: None,
+        timeouts: None,
     };
     let response = Response {
         status_code: 200,
@@ -65,6 +67,8 @@ fn test_format_request() {
         version: Version::Http11,
         headers: vec![("Content-Type".to_string(), "text/json".to_string())],
         body: Some("{\"resp\":\"great-resp\"}".to_string()),
+        redirects: vec![],
+        proxy: None,
     };
     let empty_format = parse_format("").expect("valid format");
 
@@ -134,3 +138,81 @@ Content-Type: text/json
         "GET localhost:8080\nHTTP/1.1 200 Ok\n"
     );
 }
+
+#[test]
+fn test_format_masks_secrets() {
+    let request = Request {
+        method: Method::Get,
+        target: "localhost:8080/?key=s3cr3t".to_string(),
+        headers: vec![("Authorization".to_string(), "Bearer t0ken".to_string())],
+        body: None,
+        redirects: None,
+        timeouts: None,
+    };
+    let response = Response {
+        status_code: 200,
+        status: "200 Ok".to_string(),
+        version: Version::Http11,
+        headers: vec![],
+        body: Some("{\"echo\":\"t0ken\"}".to_string()),
+        redirects: vec![],
+        proxy: None,
+    };
+    let full_format = parse_format("%R\n%H\n%B\n").expect("valid format");
+    let mut buffer = Vec::new();
+    let mut outputter = FormattedOutputter::new(&mut buffer, full_format.clone(), full_format);
+    outputter.mask("s3cr3t");
+    outputter.mask("t0ken");
+    outputter.mask("t0ken");
+    outputter.mask("");
+    outputter.request(&request).expect("print works correctly");
+    outputter
+        .response(&response)
+        .expect("print works correctly");
+    assert_eq!(
+        String::from_utf8(buffer).expect("is a string"),
+        r#"GET localhost:8080/?key=********
+Authorization: Bearer ********
+
+
+HTTP/1.1 200 Ok
+
+{
+  "echo": "********"
+}
+"#
+    );
+}
+
+#[test]
+fn test_format_redirects() {
+    let response = Response {
+        status_code: 200,
+        status: "200 OK".to_string(),
+        version: Version::Http11,
+        headers: vec![],
+        body: None,
+        redirects: vec![Redirect {
+            url: "http://localhost/old".to_string(),
+            version: Version::Http11,
+            status_code: 301,
+            status: "301 Moved Permanently".to_string(),
+            location: "/new".to_string(),
+            headers: vec![("location".to_string(), "/new".to_string())],
+        }],
+        proxy: None,
+    };
+    let mut buffer = Vec::new();
+    let mut outputter = FormattedOutputter::new(
+        &mut buffer,
+        parse_format("").expect("valid format"),
+        parse_format("%R\n%H").expect("valid format"),
+    );
+    outputter
+        .response(&response)
+        .expect("print works correctly");
+    assert_eq!(
+        String::from_utf8(buffer).expect("is a string"),
+        "HTTP/1.1 301 Moved Permanently -> /new\nlocation: /new\nHTTP/1.1 200 OK\n"
+    );
+}
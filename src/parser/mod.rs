@@ -1,4 +1,5 @@
 #[cfg(test)]
+#[allow(clippy::to_string_in_format_args)]
 pub mod tests;
 
 use crate::Result;
@@ -18,6 +19,7 @@ struct ScriptParser;
 #[derive(Debug)]
 pub struct Error {
     pub message: String,
+    #[allow(dead_code)]
     pub selection: Selection,
 }
 
@@ -67,29 +69,74 @@ impl ToSelection for LineColLocation {
 impl FromPair for Handler {
     fn from_pair(filename: PathBuf, pair: Pair<'_, Rule>) -> Self {
         match pair.as_rule() {
-            Rule::response_handler => Handler {
-                selection: pair.as_span().to_selection(filename),
-                script: pair
-                    .into_inner()
-                    .find_map(|pair| match pair.as_rule() {
-                        Rule::handler_script => Some(
-                            pair.into_inner()
-                                .find_map(|pair| match pair.as_rule() {
-                                    Rule::handler_script_string => Some(pair.as_str()),
-                                    _ => None,
-                                })
-                                .unwrap(),
-                        ),
-                        _ => None,
-                    })
-                    .unwrap()
-                    .to_string(),
-            },
+            Rule::response_handler | Rule::pre_request_handler => {
+                let selection = pair.as_span().to_selection(filename.clone());
+                let pair = pair.into_inner().next().unwrap();
+                match pair.as_rule() {
+                    Rule::handler_script | Rule::pre_request_script => {
+                        let script = pair
+                            .into_inner()
+                            .find(|pair| pair.as_rule() == Rule::handler_script_string)
+                            .unwrap();
+                        Handler {
+                            // Select the script itself so positions within it can be mapped to
+                            // the file
+                            selection: script.as_span().to_selection(filename),
+                            script: script.as_str().to_string(),
+                            path: None,
+                        }
+                    }
+                    Rule::handler_file | Rule::pre_request_file => {
+                        let path = pair.into_inner().next().unwrap().as_str();
+                        Handler {
+                            selection,
+                            script: String::new(),
+                            path: Some(
+                                // Collecting the components drops the `.` in `./script.js`
+                                filename
+                                    .parent()
+                                    .map(|directory| directory.join(path))
+                                    .unwrap_or_else(|| PathBuf::from(path))
+                                    .components()
+                                    .collect(),
+                            ),
+                        }
+                    }
+                    _ => invalid_pair(Rule::handler_script, pair.as_rule()),
+                }
+            }
             _ => invalid_pair(Rule::response_handler, pair.as_rule()),
         }
     }
 }
 
+impl FromPair for Directive {
+    fn from_pair(filename: PathBuf, pair: Pair<'_, Rule>) -> Self {
+        match pair.as_rule() {
+            Rule::directive => {
+                let selection = pair.as_span().to_selection(filename);
+                let mut pairs = pair.into_inner();
+                Directive {
+                    selection,
+                    name: pairs
+                        .find_map(|pair| match pair.as_rule() {
+                            Rule::directive_name => Some(pair.as_str().to_string()),
+                            _ => None,
+                        })
+                        .unwrap(),
+                    value: pairs
+                        .find_map(|pair| match pair.as_rule() {
+                            Rule::directive_value => Some(pair.as_str().trim().to_string()),
+                            _ => None,
+                        })
+                        .unwrap_or_default(),
+                }
+            }
+            _ => invalid_pair(Rule::directive, pair.as_rule()),
+        }
+    }
+}
+
 impl FromPair for Method {
     fn from_pair(filename: PathBuf, pair: Pair<'_, Rule>) -> Self {
         let selection = pair.as_span().to_selection(filename);
@@ -233,6 +280,18 @@ impl FromPair for RequestScript {
                 let mut pairs = pair.clone().into_inner();
                 RequestScript {
                     selection: pair.as_span().to_selection(filename.clone()),
+                    directives: pairs
+                        .clone()
+                        .filter(|pair| pair.as_rule() == Rule::directive)
+                        .map(|pair| Directive::from_pair(filename.clone(), pair))
+                        .collect::<Vec<Directive>>(),
+                    pre_request: {
+                        let pair = pairs.clone().find_map(|pair| match pair.as_rule() {
+                            Rule::pre_request_handler => Some(pair),
+                            _ => None,
+                        });
+                        pair.map(|pair| Handler::from_pair(filename.clone(), pair))
+                    },
                     request: Request::from_pair(filename.clone(), pair),
                     handler: {
                         let pair = pairs.find_map(|pair| match pair.as_rule() {
@@ -330,17 +389,27 @@ pub struct File {
 
 #[derive(Debug)]
 pub struct RequestScript {
+    pub directives: Vec<Directive>,
+    pub pre_request: Option<Handler>,
     pub request: Request,
     pub handler: Option<Handler>,
     pub selection: Selection,
 }
 
+#[derive(Debug, Clone)]
+pub struct Directive {
+    pub name: String,
+    pub value: String,
+    pub selection: Selection,
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
     pub target: Value,
     pub headers: Vec<Header>,
     pub body: Option<Value>,
+    #[allow(dead_code)]
     pub selection: Selection,
 }
 
@@ -358,6 +427,7 @@ pub enum Method {
 pub struct Header {
     pub field_name: String,
     pub field_value: Value,
+    #[allow(dead_code)]
     pub selection: Selection,
 }
 
@@ -365,6 +435,24 @@ pub struct Header {
 pub struct Handler {
     pub script: String,
     pub selection: Selection,
+    /// The file holding the script, resolved relative to the `.http` file, when it isn't inline
+    pub path: Option<PathBuf>,
+}
+
+impl Display for Selection {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        if self.filename.as_os_str().is_empty() {
+            fmt.write_str("<internal>")
+        } else {
+            write!(
+                fmt,
+                "{}:{}:{}",
+                self.filename.display(),
+                self.start.line,
+                self.start.col
+            )
+        }
+    }
 }
 
 impl Selection {
@@ -377,6 +465,14 @@ impl Selection {
     }
 }
 
+impl RequestScript {
+    pub fn directive(&self, name: &str) -> Option<&Directive> {
+        self.directives
+            .iter()
+            .find(|directive| directive.name == name)
+    }
+}
+
 impl File {
     pub fn request_scripts(
         &self,
@@ -267,3 +267,130 @@ header: some-value";
     assert!(&request.headers[0].field_name == "header");
     assert!(&request.body.is_none());
 }
+
+#[test]
+fn directives() {
+    let test = "\
+# Comment
+# @name login
+# @no-value
+POST http://example.com HTTP/1.1
+
+###
+
+# @name   second  
+GET http://example.com HTTP/1.1
+";
+
+    let file = parser::parse(PathBuf::default(), test);
+    if let Err(e) = &file {
+        println!("{:?}", e);
+    }
+
+    let file = file.unwrap();
+    let first = &file.request_scripts[0];
+    assert_eq!(first.directives.len(), 2);
+    assert_eq!(first.directive("name").unwrap().value, "login");
+    assert_eq!(first.directive("no-value").unwrap().value, "");
+    assert_eq!(first.selection.start.line, 2);
+
+    let second = &file.request_scripts[1];
+    assert_eq!(second.directive("name").unwrap().value, "second");
+    assert!(second.directive("no-value").is_none());
+}
+
+#[test]
+fn pre_request_handler() {
+    let test = "\
+# @name signed
+< {%
+    client.global.set('signature', 'abc');
+%}
+POST http://example.com HTTP/1.1
+X-Signature: {{signature}}
+
+{}
+
+> {%
+    console.log('cool');
+%}
+";
+
+    let file = parser::parse(PathBuf::default(), test);
+    if let Err(e) = &file {
+        println!("{:?}", e);
+    }
+
+    let file = file.unwrap();
+    let request_script = &file.request_scripts[0];
+    assert_eq!(
+        request_script.pre_request.as_ref().unwrap().script,
+        "client.global.set('signature', 'abc');"
+    );
+    assert_eq!(
+        request_script.handler.as_ref().unwrap().script,
+        "console.log('cool');"
+    );
+    assert_eq!(request_script.request.headers[0].field_name, "X-Signature");
+}
+
+#[test]
+fn external_handlers() {
+    let test = "\
+< ./pre/sign.js
+POST http://example.com HTTP/1.1
+
+{}
+
+> ../handlers/extract-token.js
+";
+
+    let file = parser::parse(PathBuf::from("/tmp/api/requests.http"), test);
+    if let Err(e) = &file {
+        println!("{:?}", e);
+    }
+
+    let file = file.unwrap();
+    let request_script = &file.request_scripts[0];
+    let pre_request = request_script.pre_request.as_ref().unwrap();
+    assert_eq!(
+        pre_request.path,
+        Some(PathBuf::from("/tmp/api/pre/sign.js"))
+    );
+    assert_eq!(pre_request.script, "");
+    let handler = request_script.handler.as_ref().unwrap();
+    assert_eq!(
+        handler.path,
+        Some(PathBuf::from("/tmp/api/../handlers/extract-token.js"))
+    );
+    assert!(request_script.request.body.is_some());
+}
+
+#[test]
+fn handler_file_in_body() {
+    let test = "\
+POST http://example.com HTTP/1.1
+
+<path> /usr/bin</path>
+<path> ./bin</path>
+
+> ./handler.js
+";
+
+    let file = parser::parse(PathBuf::from("/tmp/api/requests.http"), test);
+    if let Err(e) = &file {
+        println!("{:?}", e);
+    }
+
+    let file = file.unwrap();
+    let request_script = &file.request_scripts[0];
+    let body = match &request_script.request.body.as_ref().unwrap().state {
+        parser::Unprocessed::WithoutInline(body, _) => body,
+        parser::Unprocessed::WithInline { value, .. } => value,
+    };
+    assert_eq!(body.trim(), "<path> /usr/bin</path>\n<path> ./bin</path>");
+    assert_eq!(
+        request_script.handler.as_ref().unwrap().path,
+        Some(PathBuf::from("/tmp/api/handler.js"))
+    );
+}
@@ -0,0 +1,221 @@
+use crate::secrets::MASK;
+use crate::Result;
+use anyhow::Context;
+use reqwest::Url;
+use serde_json::Value;
+use std::net::IpAddr;
+
+#[cfg(test)]
+mod tests;
+
+/// The key of the section of an environment configuring the proxy, which isn't a variable
+pub const SECTION: &str = "$proxy";
+
+/// The schemes a proxy URL may have
+const SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/// Where requests are sent through. Set from the command line, the `$proxy` section of the
+/// environment, then the standard environment variables, each filling what the previous left unset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxyConfig {
+    /// The proxy of every request, an `http://`, `https://` or `socks5://` URL
+    pub url: Option<String>,
+    /// The proxy of `http://` requests, over `url`
+    pub http: Option<String>,
+    /// The proxy of `https://` requests, over `url`
+    pub https: Option<String>,
+    /// The hosts requests go to directly: `localhost`, `example.com` and its subdomains,
+    /// `*.example.com`, `10.0.0.0/8`, `host:8080`, or `*` for every host
+    pub no_proxy: Vec<String>,
+    /// Credentials sent to the proxy, they may also be given in its URL
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl ProxyConfig {
+    /// The `$proxy` section of an environment, either a URL or an object with the fields of
+    /// [`ProxyConfig`], `noProxy` being an array or a comma separated list
+    pub fn from_section(section: &Value) -> Result<ProxyConfig> {
+        let invalid = || anyhow!("Invalid {} section, expected a URL or an object", SECTION);
+        let object = match section {
+            Value::String(url) => {
+                return Ok(ProxyConfig {
+                    url: Some(url.clone()),
+                    ..ProxyConfig::default()
+                })
+            }
+            Value::Object(object) => object,
+            _ => return Err(invalid()),
+        };
+        let mut config = ProxyConfig::default();
+        for (key, value) in object {
+            let field = match key.as_str() {
+                "url" => &mut config.url,
+                "http" => &mut config.http,
+                "https" => &mut config.https,
+                "username" => &mut config.username,
+                "password" => &mut config.password,
+                "noProxy" => {
+                    config.no_proxy = match value {
+                        Value::String(hosts) => split_hosts(hosts),
+                        Value::Array(hosts) => hosts
+                            .iter()
+                            .map(|host| host.as_str().map(str::to_string).ok_or_else(invalid))
+                            .collect::<Result<_>>()?,
+                        _ => return Err(invalid()),
+                    };
+                    continue;
+                }
+                key => return Err(anyhow!("Unknown {} setting: {}", SECTION, key)),
+            };
+            *field = Some(value.as_str().ok_or_else(invalid)?.to_string());
+        }
+        Ok(config)
+    }
+
+    /// The proxy of the `ALL_PROXY`, `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment
+    /// variables, or of their lowercase variants
+    pub fn from_env() -> ProxyConfig {
+        let var = |name: &str| {
+            std::env::var(name)
+                .or_else(|_| std::env::var(name.to_ascii_lowercase()))
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        ProxyConfig {
+            url: var("ALL_PROXY"),
+            http: var("HTTP_PROXY"),
+            https: var("HTTPS_PROXY"),
+            no_proxy: var("NO_PROXY")
+                .map(|hosts| split_hosts(&hosts))
+                .unwrap_or_default(),
+            username: None,
+            password: None,
+        }
+    }
+
+    /// Fills what this config leaves unset with `fallback`
+    pub fn or(self, fallback: ProxyConfig) -> ProxyConfig {
+        ProxyConfig {
+            url: self.url.or(fallback.url),
+            http: self.http.or(fallback.http),
+            https: self.https.or(fallback.https),
+            no_proxy: if self.no_proxy.is_empty() {
+                fallback.no_proxy
+            } else {
+                self.no_proxy
+            },
+            username: self.username.or(fallback.username),
+            password: self.password.or(fallback.password),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.url.is_some() || self.http.is_some() || self.https.is_some()
+    }
+
+    /// Fails when one of the proxy URLs is invalid
+    pub fn validate(&self) -> Result<()> {
+        for url in [&self.url, &self.http, &self.https]
+            .iter()
+            .copied()
+            .flatten()
+        {
+            let parsed = Url::parse(url).with_context(|| format!("Invalid proxy URL: {}", url))?;
+            if !SCHEMES.contains(&parsed.scheme()) {
+                return Err(anyhow!(
+                    "Invalid proxy URL: {}, expected one of the schemes {}",
+                    url,
+                    SCHEMES.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The proxy a request to `url` goes through, with the credentials, `None` when it goes to
+    /// the server directly
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str()?;
+        if self
+            .no_proxy
+            .iter()
+            .any(|pattern| no_proxy_matches(pattern, host, url.port_or_known_default()))
+        {
+            return None;
+        }
+        let proxy = match url.scheme() {
+            "https" => self.https.as_ref(),
+            _ => self.http.as_ref(),
+        };
+        let mut proxy = Url::parse(proxy.or(self.url.as_ref())?).ok()?;
+        if let Some(username) = &self.username {
+            proxy.set_username(username).ok()?;
+            proxy.set_password(self.password.as_deref()).ok()?;
+        }
+        Some(proxy)
+    }
+}
+
+/// A proxy URL as output, its password masked
+pub fn display(proxy: &Url) -> String {
+    let mut proxy = proxy.clone();
+    if proxy.password().is_some() {
+        let _ = proxy.set_password(Some(MASK));
+    }
+    proxy.to_string()
+}
+
+fn split_hosts(hosts: &str) -> Vec<String> {
+    hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether a `NO_PROXY` pattern covers a host
+fn no_proxy_matches(pattern: &str, host: &str, port: Option<u16>) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if pattern == "*" {
+        return true;
+    }
+    if let Some((network, prefix)) = pattern.split_once('/') {
+        return match (network.parse(), prefix.parse(), host.parse()) {
+            (Ok(network), Ok(prefix), Ok(address)) => in_network(address, network, prefix),
+            _ => false,
+        };
+    }
+    let pattern = match pattern.rsplit_once(':') {
+        Some((pattern, pattern_port)) if !pattern.contains(':') => {
+            if pattern_port.parse().ok() != port {
+                return false;
+            }
+            pattern
+        }
+        _ => pattern,
+    };
+    let pattern = pattern
+        .trim_start_matches('*')
+        .trim_start_matches('.')
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    host == pattern || host.ends_with(&format!(".{}", pattern))
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u32) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{Response, Result};
+use std::time::{Duration, SystemTime};
+
+#[cfg(test)]
+mod tests;
+
+/// A `# @retry max=20 interval=2s until={% response.body.state === 'done' %}` directive, the
+/// request is run again until the condition holds or it was run `max` times
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polling {
+    pub max: u32,
+    pub interval: Duration,
+    /// A script evaluated with the response, without one the request is run again until its
+    /// status is successful
+    pub until: Option<String>,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Polling {
+            max: 10,
+            interval: Duration::from_secs(1),
+            until: None,
+        }
+    }
+}
+
+impl Polling {
+    pub fn parse(value: &str) -> Result<Polling> {
+        let mut polling = Polling::default();
+        let mut rest = value.trim();
+        while !rest.is_empty() {
+            let (option, value) = rest
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected name=value, found: {}", rest))?;
+            let value = value.trim_start();
+            let (value, next) = match value.strip_prefix("{%") {
+                Some(script) => {
+                    let end = script
+                        .find("%}")
+                        .ok_or_else(|| anyhow!("Expected %}} to end the {} script", option))?;
+                    (script[..end].trim(), &script[end + 2..])
+                }
+                None => value.split_at(value.find(char::is_whitespace).unwrap_or(value.len())),
+            };
+            match option.trim() {
+                "max" => {
+                    polling.max = match value.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(anyhow!("Expected a positive max, found: {}", value))
+                        }
+                        Ok(max) => max,
+                    }
+                }
+                "interval" => polling.interval = parse_duration(value)?,
+                "until" => polling.until = Some(value.to_string()),
+                option => return Err(anyhow!("Unknown retry option: {}", option)),
+            }
+            rest = next.trim_start();
+        }
+        Ok(polling)
+    }
+}
+
+/// How requests failing with a connection error or one of `statuses` are sent again, waiting
+/// `backoff` and twice as long after each attempt, or as long as the `Retry-After` header asks
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a request is sent again, 0 disables retrying
+    pub retries: u32,
+    pub statuses: Vec<u16>,
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            statuses: vec![429, 503],
+            backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before sending the request again, `attempt` counts from 0
+    pub fn delay(&self, attempt: u32, response: Option<&Response>) -> Duration {
+        response
+            .and_then(retry_after)
+            .unwrap_or_else(|| self.backoff.saturating_mul(1 << attempt.min(16)))
+    }
+
+    pub fn retries_status(&self, status: u16) -> bool {
+        self.statuses.contains(&status)
+    }
+}
+
+/// The `Retry-After` header of a response, in seconds or as an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let (_, value) = response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))?;
+    let value = value.trim();
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            )
+        }
+    }
+}
+
+/// Parses `500ms`, `2s` or `1m`, a number without a unit is in seconds
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration: {}", value))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(anyhow!("Invalid duration: {}", value)),
+    }
+}
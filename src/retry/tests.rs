@@ -0,0 +1,95 @@
+use super::*;
+use crate::Version;
+
+fn response(headers: &[(&str, &str)]) -> Response {
+    Response {
+        version: Version::Http11,
+        status_code: 503,
+        status: "503 Service Unavailable".to_string(),
+        headers: headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        body: None,
+        redirects: vec![],
+        proxy: None,
+    }
+}
+
+#[test]
+fn test_parse_polling() {
+    assert_eq!(
+        Polling::parse("max=20 interval=2s until={% response.body.state === 'done' %}").unwrap(),
+        Polling {
+            max: 20,
+            interval: Duration::from_secs(2),
+            until: Some("response.body.state === 'done'".to_string()),
+        }
+    );
+    assert_eq!(
+        Polling::parse("until={%response.status == 200%} interval=250ms").unwrap(),
+        Polling {
+            interval: Duration::from_millis(250),
+            until: Some("response.status == 200".to_string()),
+            ..Polling::default()
+        }
+    );
+    assert_eq!(Polling::parse("").unwrap(), Polling::default());
+}
+
+#[test]
+fn test_parse_polling_errors() {
+    let error = Polling::parse("max=0").unwrap_err();
+    assert_eq!(error.to_string(), "Expected a positive max, found: 0");
+
+    let error = Polling::parse("tries=3").unwrap_err();
+    assert_eq!(error.to_string(), "Unknown retry option: tries");
+
+    let error = Polling::parse("until={% response.status == 200").unwrap_err();
+    assert_eq!(error.to_string(), "Expected %} to end the until script");
+
+    let error = Polling::parse("max").unwrap_err();
+    assert_eq!(error.to_string(), "Expected name=value, found: max");
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+    assert_eq!(parse_duration("3").unwrap(), Duration::from_secs(3));
+    assert_eq!(parse_duration("1m").unwrap(), Duration::from_secs(60));
+    assert!(parse_duration("1h").is_err());
+    assert!(parse_duration("s").is_err());
+}
+
+#[test]
+fn test_delay() {
+    let policy = RetryPolicy {
+        retries: 3,
+        backoff: Duration::from_millis(100),
+        ..RetryPolicy::default()
+    };
+
+    assert_eq!(policy.delay(0, None), Duration::from_millis(100));
+    assert_eq!(policy.delay(2, None), Duration::from_millis(400));
+    assert_eq!(
+        policy.delay(2, Some(&response(&[]))),
+        Duration::from_millis(400)
+    );
+    assert_eq!(
+        policy.delay(0, Some(&response(&[("Retry-After", "7")]))),
+        Duration::from_secs(7)
+    );
+    assert_eq!(
+        policy.delay(
+            0,
+            Some(&response(&[(
+                "retry-after",
+                "Wed, 21 Oct 2015 07:28:00 GMT"
+            )]))
+        ),
+        Duration::ZERO
+    );
+    assert!(policy.retries_status(429));
+    assert!(!policy.retries_status(500));
+}
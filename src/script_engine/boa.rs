@@ -1,49 +1,69 @@
-use crate::script_engine::{handle, Script, ScriptEngine};
+use crate::script_engine::limits::{self, Budget, Limits};
+use crate::script_engine::natives::{self, Native};
+use crate::script_engine::{handle, initial_globals, Control, Script, ScriptEngine};
 
+use boa::builtins::object::{ObjectKind, INSTANCE_PROTOTYPE, PROTOTYPE};
+use boa::builtins::property::Property;
+use boa::builtins::value::{ResultValue, Value, ValueData};
 use boa::exec::Executor;
 use boa::exec::Interpreter;
 use boa::realm::Realm;
-use boa::syntax::ast::node::Node;
+use boa::syntax::ast::constant::Const;
+use boa::syntax::ast::node::{Node, PropertyDefinition};
+use boa::syntax::ast::op::{BinOp, LogOp};
+use boa::syntax::ast::pos::Position;
 use boa::syntax::lexer::Lexer;
+use boa::syntax::parser::error::ParseError;
 use boa::syntax::parser::Parser;
 
 use crate::{Response, Result};
+use std::cell::RefCell;
 use std::convert::From;
 
+thread_local! {
+    /// The budget of the script being run, natives have no access to the engine
+    static BUDGET: RefCell<Option<Budget>> = const { RefCell::new(None) };
+}
+
 pub struct BoaScriptEngine {
     interpreter: Interpreter,
-    env_script: String,
-    env: String,
+    limits: Limits,
 }
 
 impl BoaScriptEngine {
-    pub fn new(env_script: &str, env: &str, snapshot_script: &str) -> Result<BoaScriptEngine> {
-        let realm = Realm::create();
+    pub fn new(
+        env_script: &str,
+        env: &str,
+        snapshot_script: &str,
+        overrides_script: &str,
+        limits: &Limits,
+    ) -> Result<BoaScriptEngine> {
+        let mut realm = Realm::create();
+        macro_rules! register {
+            ($realm:ident, $name:literal, $kind:ident($native:ident)) => {{
+                fn native(
+                    this: &mut Value,
+                    args: &[Value],
+                    interpreter: &mut Interpreter,
+                ) -> ResultValue {
+                    call_native(Native::$kind(natives::$native), this, args, interpreter)
+                }
+                $realm = $realm.register_global_func($name, native);
+            }};
+        }
+        for_each_native!(register, realm);
+        let realm = realm.register_global_func("_tick", tick);
         let interpreter: Interpreter = Executor::new(realm);
 
         let mut engine = BoaScriptEngine {
             interpreter,
-            env_script: env_script.to_string(),
-            env: env.to_string(),
+            limits: limits.clone(),
         };
 
-        let environment: serde_json::Value = serde_json::from_str(env_script)?;
-        if let Some(environment) = environment.get(env) {
-            declare(&mut engine, environment)?;
-            let script = format!(
-                r#"
-            var _env_file = {};
-            var _env = _env_file['{}'];
-            "#,
-                &env_script, &env
-            );
-            engine.execute_script(&Script::internal_script(script.as_str()))?;
-        }
-
-        let snapshot: serde_json::Value = serde_json::from_str(snapshot_script)?;
-        declare(&mut engine, &snapshot)?;
-        let snapshot = format!("var _snapshot = {};", snapshot);
-        engine.execute_script(&Script::internal_script(snapshot.as_str()))?;
+        let globals = initial_globals(env_script, env, snapshot_script, overrides_script)?
+            .into_iter()
+            .map(|(name, value)| (name, from_json(&value, &engine.interpreter)));
+        define(&engine.interpreter.realm.global_obj, globals);
 
         let script = include_str!("init.js");
         engine.execute_script(&Script::internal_script(script))?;
@@ -52,39 +72,430 @@ impl BoaScriptEngine {
     }
 }
 
-fn parser_expr(src: &str) -> Result<Node> {
-    let mut lexer = Lexer::new(src);
+fn parser_expr(script: &Script) -> Result<Node> {
+    let mut lexer = Lexer::new(script.src);
     lexer.lex()?;
     let tokens = lexer.tokens;
     let node = Parser::new(&tokens)
         .parse_all()
-        .map_err(|e| anyhow!("ParsingError: {}", e))?;
+        .map_err(|e| match position(&e) {
+            Some(position) if !script.selection.filename.as_os_str().is_empty() => {
+                let selection = script.locate(
+                    position.line_number as usize,
+                    position.column_number as usize,
+                );
+                anyhow!("ParsingError: {} at {}", e, selection)
+            }
+            _ => anyhow!("ParsingError: {}", e),
+        })?;
     Ok(node)
 }
 
+/// Where in the script a parsing error is, Boa keeps no position past parsing, so errors thrown
+/// while running have none
+fn position(error: &ParseError) -> Option<&Position> {
+    match error {
+        ParseError::Expected(_, token, _) | ParseError::Unexpected(token, _) => Some(&token.pos),
+        ParseError::ExpectedExpr(_, _, position)
+        | ParseError::UnexpectedKeyword(_, position)
+        | ParseError::General(_, Some(position)) => Some(position),
+        _ => None,
+    }
+}
+
+/// Boa panics when a `var` is declared twice in the same scope. As the context now lives for the
+/// whole run, re-running a handler would do just that, as would a loop declaring one in its body.
+/// So the `var`s a script declares outside of functions, wherever they are nested, are declared
+/// once up front when they don't exist yet, and the declarations themselves become assignments.
+/// Declarations of functions that already exist are turned into assignments too.
+fn redeclare(node: &Node, interpreter: &Interpreter) -> Node {
+    let mut declared = vec![];
+    let node = hoist(node, interpreter, &mut declared);
+    if declared.is_empty() {
+        return node;
+    }
+    let declaration = Node::VarDecl(declared.into_iter().map(|name| (name, None)).collect());
+    Node::StatementList(Box::new([declaration, node]))
+}
+
+/// Rewrites the declarations found in every statement that can hold some, without going into
+/// functions, which have a scope of their own, collecting the names still to be declared
+fn hoist(node: &Node, interpreter: &Interpreter, declared: &mut Vec<String>) -> Node {
+    let boxed =
+        |node: &Node, declared: &mut Vec<String>| Box::new(hoist(node, interpreter, declared));
+    match node {
+        Node::StatementList(nodes) => Node::StatementList(
+            nodes
+                .iter()
+                .map(|node| hoist(node, interpreter, declared))
+                .collect(),
+        ),
+        Node::Block(nodes) => Node::Block(
+            nodes
+                .iter()
+                .map(|node| hoist(node, interpreter, declared))
+                .collect(),
+        ),
+        Node::If(condition, then, otherwise) => Node::If(
+            condition.clone(),
+            boxed(then, declared),
+            otherwise.as_deref().map(|node| boxed(node, declared)),
+        ),
+        Node::WhileLoop(condition, body) => {
+            Node::WhileLoop(condition.clone(), boxed(body, declared))
+        }
+        Node::DoWhileLoop(body, condition) => {
+            Node::DoWhileLoop(boxed(body, declared), condition.clone())
+        }
+        Node::ForLoop(init, condition, step, body) => Node::ForLoop(
+            init.as_deref().map(|node| boxed(node, declared)),
+            condition.clone(),
+            step.clone(),
+            boxed(body, declared),
+        ),
+        Node::Switch(value, cases, default) => Node::Switch(
+            value.clone(),
+            cases
+                .iter()
+                .map(|(case, body)| {
+                    let body = body
+                        .iter()
+                        .map(|node| hoist(node, interpreter, declared))
+                        .collect();
+                    (case.clone(), body)
+                })
+                .collect(),
+            default.as_deref().map(|node| boxed(node, declared)),
+        ),
+        Node::Try(block, catch, parameter, finally) => Node::Try(
+            boxed(block, declared),
+            catch.as_deref().map(|node| boxed(node, declared)),
+            parameter.clone(),
+            finally.as_deref().map(|node| boxed(node, declared)),
+        ),
+        Node::VarDecl(vars) => {
+            let environment = &interpreter.realm.environment;
+            let mut nodes: Vec<Node> = vars
+                .iter()
+                .filter_map(|(name, value)| {
+                    let existing = environment.has_binding(name);
+                    if !existing && !declared.contains(name) {
+                        declared.push(name.clone());
+                    }
+                    value.clone().map(|value| match existing {
+                        true => assign(name, value, interpreter),
+                        false => Node::Assign(Box::new(Node::Local(name.clone())), Box::new(value)),
+                    })
+                })
+                .collect();
+            // a declaration evaluates to undefined, unlike the assignments replacing it
+            nodes.push(Node::Const(Const::Undefined));
+            Node::StatementList(nodes.into_boxed_slice())
+        }
+        Node::FunctionDecl(name, params, body)
+            if interpreter.realm.environment.has_binding(name) =>
+        {
+            let function = Node::FunctionExpr(Some(name.clone()), params.clone(), body.clone());
+            assign(name, function, interpreter)
+        }
+        node => node.clone(),
+    }
+}
+
+/// Boa keeps declared globals apart from the properties of the global object, and cannot assign to
+/// the latter through a plain identifier, so those are assigned as `this[name]`
+fn assign(name: &str, value: Node, interpreter: &Interpreter) -> Node {
+    let target = if interpreter.realm.global_obj.has_field(name) {
+        Node::GetConstField(Box::new(Node::This), name.to_string())
+    } else {
+        Node::Local(name.to_string())
+    };
+    Node::Assign(Box::new(target), Box::new(value))
+}
+
+fn tick(_this: &mut Value, _args: &[Value], _interpreter: &mut Interpreter) -> ResultValue {
+    BUDGET.with(
+        |budget| match budget.borrow_mut().as_mut().and_then(Budget::tick) {
+            Some(exceeded) => Err(Value::from(format!("Exceeded the {}", exceeded))),
+            None => Ok(Value::from(true)),
+        },
+    )
+}
+
+/// Boa has no hook to interrupt a running script, so every loop condition and function body calls
+/// `_tick()`, which throws once the script goes over its limits
+fn instrument(node: &Node) -> Node {
+    let all = |nodes: &[Node]| nodes.iter().map(instrument).collect::<Box<[Node]>>();
+    let boxed = |node: &Node| Box::new(instrument(node));
+    let optional = |node: &Option<Box<Node>>| node.as_deref().map(boxed);
+    let variables = |vars: &[(String, Option<Node>)]| {
+        vars.iter()
+            .map(|(name, value)| (name.clone(), value.as_ref().map(instrument)))
+            .collect()
+    };
+    match node {
+        Node::ArrayDecl(nodes) => Node::ArrayDecl(all(nodes)),
+        Node::ArrowFunctionDecl(params, body) => {
+            Node::ArrowFunctionDecl(params.clone(), boxed(body))
+        }
+        Node::Assign(target, value) => Node::Assign(boxed(target), boxed(value)),
+        Node::BinOp(op, left, right) => Node::BinOp(op.clone(), boxed(left), boxed(right)),
+        Node::Block(nodes) => Node::Block(all(nodes)),
+        Node::Call(function, args) => Node::Call(boxed(function), all(args)),
+        Node::ConditionalOp(condition, then, otherwise) => {
+            Node::ConditionalOp(boxed(condition), boxed(then), boxed(otherwise))
+        }
+        Node::ConstDecl(vars) => Node::ConstDecl(
+            vars.iter()
+                .map(|(name, value)| (name.clone(), instrument(value)))
+                .collect(),
+        ),
+        Node::DoWhileLoop(body, condition) => {
+            Node::DoWhileLoop(boxed(body), Box::new(ticked_condition(Some(condition))))
+        }
+        Node::FunctionDecl(name, params, body) => {
+            Node::FunctionDecl(name.clone(), params.clone(), Box::new(ticked(body)))
+        }
+        Node::FunctionExpr(name, params, body) => {
+            Node::FunctionExpr(name.clone(), params.clone(), Box::new(ticked(body)))
+        }
+        Node::GetConstField(object, field) => Node::GetConstField(boxed(object), field.clone()),
+        Node::GetField(object, field) => Node::GetField(boxed(object), boxed(field)),
+        Node::ForLoop(init, condition, step, body) => Node::ForLoop(
+            optional(init),
+            Some(Box::new(ticked_condition(condition.as_deref()))),
+            optional(step),
+            boxed(body),
+        ),
+        Node::If(condition, then, otherwise) => {
+            Node::If(boxed(condition), boxed(then), optional(otherwise))
+        }
+        Node::LetDecl(vars) => Node::LetDecl(variables(vars)),
+        Node::New(call) => Node::New(boxed(call)),
+        Node::Object(properties) => Node::Object(
+            properties
+                .iter()
+                .map(|property| match property {
+                    PropertyDefinition::Property(name, value) => {
+                        PropertyDefinition::Property(name.clone(), instrument(value))
+                    }
+                    PropertyDefinition::MethodDefinition(kind, name, value) => {
+                        PropertyDefinition::MethodDefinition(
+                            kind.clone(),
+                            name.clone(),
+                            instrument(value),
+                        )
+                    }
+                    PropertyDefinition::SpreadObject(value) => {
+                        PropertyDefinition::SpreadObject(instrument(value))
+                    }
+                    property => property.clone(),
+                })
+                .collect(),
+        ),
+        Node::Return(value) => Node::Return(optional(value)),
+        Node::Switch(value, cases, default) => Node::Switch(
+            boxed(value),
+            cases
+                .iter()
+                .map(|(case, body)| (instrument(case), all(body)))
+                .collect(),
+            optional(default),
+        ),
+        Node::Spread(value) => Node::Spread(boxed(value)),
+        Node::StatementList(nodes) => Node::StatementList(all(nodes)),
+        Node::Throw(value) => Node::Throw(boxed(value)),
+        Node::Try(block, catch, parameter, finally) => Node::Try(
+            boxed(block),
+            optional(catch),
+            optional(parameter),
+            optional(finally),
+        ),
+        Node::UnaryOp(op, value) => Node::UnaryOp(op.clone(), boxed(value)),
+        Node::VarDecl(vars) => Node::VarDecl(variables(vars)),
+        Node::WhileLoop(condition, body) => {
+            Node::WhileLoop(Box::new(ticked_condition(Some(condition))), boxed(body))
+        }
+        node => node.clone(),
+    }
+}
+
+fn tick_call() -> Node {
+    Node::Call(Box::new(Node::Local("_tick".to_string())), Box::new([]))
+}
+
+fn ticked_condition(condition: Option<&Node>) -> Node {
+    match condition {
+        Some(condition) => Node::BinOp(
+            BinOp::Log(LogOp::And),
+            Box::new(tick_call()),
+            Box::new(instrument(condition)),
+        ),
+        None => tick_call(),
+    }
+}
+
+fn ticked(body: &Node) -> Node {
+    Node::StatementList(Box::new([tick_call(), instrument(body)]))
+}
+
+fn call_native(
+    native: Native,
+    this: &mut Value,
+    args: &[Value],
+    interpreter: &mut Interpreter,
+) -> ResultValue {
+    let args = args.iter().map(to_json).collect::<Vec<serde_json::Value>>();
+    let result = match native {
+        Native::Function(function) => function(&args).map(Some),
+        Native::Method(method) => match to_json(this) {
+            serde_json::Value::Object(mut fields) => {
+                let result = method(&mut fields, &args);
+                for (key, value) in fields {
+                    this.set_field_slice(&key, from_json(&value, interpreter));
+                }
+                result
+            }
+            _ => Err(anyhow!("Method called without an object receiver")),
+        },
+    }
+    .map_err(|e| Value::from(e.to_string()))?;
+    match result {
+        Some(result) => Ok(from_json(&result, interpreter)),
+        None => Ok(this.clone()),
+    }
+}
+
+fn from_json(value: &serde_json::Value, interpreter: &Interpreter) -> Value {
+    let global = &interpreter.realm.global_obj;
+    match value {
+        serde_json::Value::Null => Value::null(),
+        serde_json::Value::Bool(value) => Value::from(*value),
+        serde_json::Value::Number(number) => match number.as_i64().map(i32::try_from) {
+            Some(Ok(value)) => Value::from(value),
+            _ => Value::from(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(value) => Value::from(value.as_str()),
+        serde_json::Value::Array(values) => {
+            // Built the way array literals are, so arrays get their prototype
+            let array = Value::new_object(Some(global));
+            array.set_kind(ObjectKind::Array);
+            array.set_internal_slot(
+                INSTANCE_PROTOTYPE,
+                global.get_field_slice("Array").get_field_slice(PROTOTYPE),
+            );
+            let elements = values
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (index.to_string(), from_json(value, interpreter)));
+            define(&array, elements);
+            let length = Property::new()
+                .value(Value::from(values.len() as i32))
+                .writable(true)
+                .configurable(false)
+                .enumerable(false);
+            array.set_property("length".to_string(), length);
+            array
+        }
+        serde_json::Value::Object(fields) => {
+            let object = Value::new_object(Some(global));
+            let fields = fields
+                .iter()
+                .map(|(key, value)| (key.clone(), from_json(value, interpreter)));
+            define(&object, fields);
+            object
+        }
+    }
+}
+
+/// Sets properties on an object under a single borrow. Each mutable borrow of a Boa object roots
+/// and unroots everything the object holds, so setting thousands of variables one at a time
+/// takes seconds.
+fn define(object: &Value, properties: impl Iterator<Item = (String, Value)>) {
+    let properties = properties.collect::<Vec<_>>();
+    if let ValueData::Object(object) = object.data() {
+        let mut object = object.borrow_mut();
+        for (name, value) in properties {
+            let property = Property::new()
+                .value(value)
+                .writable(true)
+                .enumerable(true)
+                .configurable(true);
+            object.properties.insert(name, property);
+        }
+    }
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    match value.data() {
+        ValueData::Null | ValueData::Undefined | ValueData::Symbol(_) => serde_json::Value::Null,
+        ValueData::Boolean(value) => serde_json::Value::Bool(*value),
+        ValueData::String(value) => serde_json::Value::String(value.clone()),
+        ValueData::Rational(value) => serde_json::Number::from_f64(*value)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        ValueData::Integer(value) => serde_json::Value::from(*value),
+        ValueData::BigInt(value) => serde_json::Value::String(value.to_string()),
+        ValueData::Object(object) => {
+            let object = object.borrow();
+            match object.kind {
+                ObjectKind::Function => serde_json::Value::Null,
+                ObjectKind::Array => {
+                    let length = value.get_field_slice("length").to_integer();
+                    serde_json::Value::Array(
+                        (0..length)
+                            .map(|index| to_json(&value.get_field_slice(&index.to_string())))
+                            .collect(),
+                    )
+                }
+                _ => serde_json::Value::Object(
+                    object
+                        .properties
+                        .keys()
+                        .filter_map(|key| {
+                            let field = value.get_field_slice(key);
+                            if field.is_function() {
+                                None
+                            } else {
+                                Some((key.clone(), to_json(&field)))
+                            }
+                        })
+                        .collect(),
+                ),
+            }
+        }
+    }
+}
+
 impl ScriptEngine for BoaScriptEngine {
     fn execute_script(&mut self, script: &Script) -> Result<String> {
         // Setup executor
-        let expr = parser_expr(script.src)?;
-        let result = self
-            .interpreter
-            .run(&expr)
+        let expr = parser_expr(script)?;
+        let expr = instrument(&redeclare(&expr, &self.interpreter));
+        BUDGET.with(|budget| *budget.borrow_mut() = Some(Budget::start(&self.limits)));
+        let result = self.interpreter.run(&expr);
+        let budget = BUDGET.with(|budget| budget.borrow_mut().take());
+        if let Some(exceeded) = budget.as_ref().and_then(Budget::exceeded) {
+            return Err(limits::exceeded(script, exceeded));
+        }
+        let result = result
             .map_err(|err| anyhow!("Error executing expression: {}", err))?
             .to_string();
         Ok(result)
     }
 
-    fn empty(&self) -> String {
-        String::from("{}")
-    }
-
-    fn reset(&mut self) -> Result<()> {
-        let snapshot = self.snapshot()?;
-        *self = BoaScriptEngine::new(
-            self.env_script.as_str(),
-            self.env.as_str(),
-            snapshot.as_str(),
-        )?;
+    fn set_global(&mut self, name: &str, value: &serde_json::Value) -> Result<()> {
+        let value = from_json(value, &self.interpreter);
+        // Globals declared with `var` are bindings, apart from the properties of the global object
+        let realm = &mut self.interpreter.realm;
+        if !realm.global_obj.has_field(name) && realm.environment.has_binding(name) {
+            realm.environment.set_mutable_binding(name, value, false);
+        } else {
+            define(
+                &realm.global_obj,
+                std::iter::once((name.to_string(), value)),
+            );
+        }
         Ok(())
     }
 
@@ -94,20 +505,16 @@ impl ScriptEngine for BoaScriptEngine {
         Ok(out)
     }
 
-    fn handle(&mut self, request_script: &Script, response: &Response) -> Result<()> {
-        handle(self, request_script, response)
+    fn cookies(&mut self) -> Result<String> {
+        // JSON.stringify writes the arrays scripts build as objects with a length
+        let cookies = self
+            .interpreter
+            .run(&parser_expr(&Script::internal_script("_cookies"))?)
+            .map_err(|err| anyhow!("Error executing expression: {}", err))?;
+        Ok(to_json(&cookies).to_string())
     }
-}
 
-fn declare(engine: &mut BoaScriptEngine, variables_object: &serde_json::Value) -> Result<()> {
-    if let serde_json::Value::Object(map) = variables_object {
-        for (key, value) in map {
-            let script = serde_json::to_string(&value)?;
-            let script = format!("this['{}'] = {};", key, script);
-            engine.execute_script(&Script::internal_script(script.as_str()))?;
-        }
-        Ok(())
-    } else {
-        Err(anyhow!("Failed to declare object: {:?}", variables_object))
+    fn handle(&mut self, request_script: &Script, response: &Response) -> Result<Control> {
+        handle(self, request_script, response)
     }
 }
@@ -1,10 +1,23 @@
+var _global = this;
+var _request = {};
+var _run = {};
 var client = {
-  global: {}
+  global: {},
+  run: {}
+};
+var request = {
+  variables: {}
 };
 client.global.set = function (key, value) {
   _snapshot[key] = value;
+  if (_overrides[key] == undefined) {
+    _global[key] = value;
+  }
 };
 client.global.get = function (key) {
+   if (_overrides[key] != undefined) {
+     return _overrides[key];
+   }
    if (_snapshot[key] != undefined) {
      return _snapshot[key];
    }
@@ -13,3 +26,170 @@ client.global.get = function (key) {
    }
    return null;
 };
+client.run.set = function (key, value) {
+  _run[key] = value;
+  _global[key] = value;
+};
+client.run.get = function (key) {
+  if (_run[key] != undefined) {
+    return _run[key];
+  }
+  return null;
+};
+request.variables.set = function (key, value) {
+  _request[key] = value;
+  _global[key] = value;
+};
+request.variables.get = function (key) {
+  if (_request[key] != undefined) {
+    return _request[key];
+  }
+  return null;
+};
+var _cookies = [];
+var _findCookie = function (name) {
+  var found = -1;
+  var i = 0;
+  while (i < _cookies.length) {
+    if (found < 0 && _cookies[i].name == name) {
+      found = i;
+    }
+    i = i + 1;
+  }
+  return found;
+};
+client.cookies = {};
+client.cookies.get = function (name) {
+  var i = _findCookie(name);
+  if (i < 0) {
+    return null;
+  }
+  return _cookies[i].value;
+};
+client.cookies.set = function (name, value) {
+  var i = _findCookie(name);
+  if (i < 0) {
+    i = _cookies.length;
+    _cookies.push({ name: name, path: '/' });
+  }
+  _cookies[i].value = '' + value;
+  if (arguments.length > 2) {
+    _cookies[i].domain = arguments[2];
+  }
+};
+client.cookies.remove = function (name) {
+  var kept = [];
+  var i = 0;
+  while (i < _cookies.length) {
+    if (_cookies[i].name != name) {
+      kept.push(_cookies[i]);
+    }
+    i = i + 1;
+  }
+  _cookies = kept;
+};
+client.cookies.clear = function () {
+  _cookies = [];
+};
+client.cookies.all = function () {
+  return _cookies;
+};
+var _control = '';
+client.skip = function () {
+  _control = 'skip';
+};
+client.exit = function () {
+  _control = 'exit 0';
+  if (arguments.length > 0) {
+    _control = 'exit ' + arguments[0];
+  }
+};
+client.goto = function (name) {
+  _control = 'goto ' + name;
+};
+var _takeControl = function (control) {
+  _control = '';
+  return control;
+};
+var _responses = {};
+var _restore = function (keys) {
+  var i = 0;
+  var key;
+  while (i < keys.length) {
+    key = keys[i];
+    if (_run[key] != undefined) {
+      _global[key] = _run[key];
+    } else if (_overrides[key] != undefined) {
+      _global[key] = _overrides[key];
+    } else if (_snapshot[key] != undefined) {
+      _global[key] = _snapshot[key];
+    } else if (_env[key] != undefined) {
+      _global[key] = _env[key];
+    } else {
+      _global[key] = undefined;
+    }
+    i = i + 1;
+  }
+};
+var _resetRequest = function () {
+  var keys = _keys(_request);
+  _request = {};
+  _restore(keys);
+  _global.response = undefined;
+};
+var _inline = function (value, format) {
+  if (typeof value == "undefined") {
+    return "";
+  }
+  return _interpolate(value, format);
+};
+var _declareVariables = function (variables) {
+  var keys = _keys(variables);
+  var i = 0;
+  while (i < keys.length) {
+    request.variables.set(keys[i], variables[keys[i]]);
+    i = i + 1;
+  }
+};
+var _resetRun = function () {
+  _resetRequest();
+  var keys = _keys(_run);
+  var names = _keys(_responses);
+  _run = {};
+  _responses = {};
+  _restore(keys);
+  _restore(names);
+};
+var _hasher = function (algorithm, hmac) {
+  return {
+    algorithm: algorithm,
+    hmac: hmac,
+    withTextSecret: _cryptoWithTextSecret,
+    withHexSecret: _cryptoWithHexSecret,
+    withBase64Secret: _cryptoWithBase64Secret,
+    updateWithText: _cryptoUpdateWithText,
+    updateWithHex: _cryptoUpdateWithHex,
+    updateWithBase64: _cryptoUpdateWithBase64,
+    digest: _cryptoDigest,
+    toHex: _cryptoToHex,
+    toBase64: _cryptoToBase64
+  };
+};
+var crypto = {
+  md5: function () { return _hasher('md5', false); },
+  sha1: function () { return _hasher('sha1', false); },
+  sha256: function () { return _hasher('sha256', false); },
+  sha384: function () { return _hasher('sha384', false); },
+  sha512: function () { return _hasher('sha512', false); },
+  hmac: {
+    md5: function () { return _hasher('md5', true); },
+    sha1: function () { return _hasher('sha1', true); },
+    sha256: function () { return _hasher('sha256', true); },
+    sha384: function () { return _hasher('sha384', true); },
+    sha512: function () { return _hasher('sha512', true); }
+  }
+};
+var Base64 = {
+  encode: _base64Encode,
+  decode: _base64Decode
+};
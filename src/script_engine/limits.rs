@@ -0,0 +1,87 @@
+use crate::script_engine::Script;
+use std::fmt;
+use std::time::Duration;
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rhai"))]
+use std::time::Instant;
+
+/// Limits applied to every script run, a script going over one of them is stopped with an error
+/// pointing at its selection
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Wall-clock time a single script may run for
+    pub timeout: Option<Duration>,
+    /// Operations a single script may perform, what counts as one depends on the engine: loop
+    /// iterations and function calls for Boa, interrupt checks for QuickJS, Rhai's own count
+    pub max_operations: Option<u64>,
+    /// Heap size, in bytes, of the engine, only enforced by QuickJS
+    pub max_heap: Option<usize>,
+}
+
+/// Tracks a single script run against the limits, V8 only has the timeout its watchdog enforces
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rhai"))]
+#[derive(Debug)]
+pub struct Budget {
+    limits: Limits,
+    deadline: Option<Instant>,
+    operations: u64,
+    exceeded: Option<Exceeded>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exceeded {
+    Timeout(Duration),
+    #[cfg(any(feature = "boa", feature = "quickjs", feature = "rhai"))]
+    Operations(u64),
+}
+
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rhai"))]
+impl Budget {
+    pub fn start(limits: &Limits) -> Budget {
+        Budget {
+            limits: limits.clone(),
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            operations: 0,
+            exceeded: None,
+        }
+    }
+
+    /// Counts one operation, returning the limit that is exceeded if any. Once a limit is
+    /// exceeded every following tick fails too, so a script can't catch its way out of it.
+    pub fn tick(&mut self) -> Option<Exceeded> {
+        self.operations += 1;
+        if self.exceeded.is_none() {
+            self.exceeded = match (self.limits.max_operations, self.limits.timeout) {
+                (Some(max_operations), _) if self.operations > max_operations => {
+                    Some(Exceeded::Operations(max_operations))
+                }
+                (_, Some(timeout)) if self.deadline.is_some_and(|d| Instant::now() > d) => {
+                    Some(Exceeded::Timeout(timeout))
+                }
+                _ => None,
+            };
+        }
+        self.exceeded
+    }
+
+    pub fn exceeded(&self) -> Option<Exceeded> {
+        self.exceeded
+    }
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exceeded::Timeout(timeout) => write!(f, "time limit of {:?}", timeout),
+            #[cfg(any(feature = "boa", feature = "quickjs", feature = "rhai"))]
+            Exceeded::Operations(operations) => write!(f, "limit of {} operations", operations),
+        }
+    }
+}
+
+pub fn exceeded(script: &Script, exceeded: Exceeded) -> anyhow::Error {
+    anyhow!(
+        "The script at {} exceeded the {}",
+        script.selection,
+        exceeded
+    )
+}
@@ -3,15 +3,29 @@ use crate::Result;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json::Map;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::str::FromStr;
+
+#[macro_use]
+mod natives;
+
+mod limits;
+pub use limits::Limits;
 
 #[cfg(feature = "boa")]
 pub mod boa;
 
+#[cfg(feature = "quickjs")]
+pub mod quickjs;
+
+#[cfg(feature = "rhai")]
+pub mod rhai;
+
 #[cfg(feature = "rusty_v8")]
 pub mod v8;
 
 #[cfg(test)]
+#[allow(clippy::needless_return)]
 mod tests;
 
 #[derive(Debug)]
@@ -31,7 +45,7 @@ pub enum Unprocessed {
         inline_scripts: Vec<InlineScript>,
         selection: Selection,
     },
-    WithoutInline(String, Selection),
+    WithoutInline(String, #[allow(dead_code)] Selection),
 }
 
 #[derive(Debug)]
@@ -41,38 +55,177 @@ pub struct InlineScript {
     pub selection: Selection,
 }
 
-pub fn create_script_engine(
+/// The script engines that can run handlers and inline scripts, each behind the cargo feature of
+/// the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Boa,
+    QuickJs,
+    Rhai,
+    V8,
+}
+
+impl Engine {
+    pub const NAMES: [&'static str; 4] = ["boa", "quickjs", "rhai", "v8"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Engine::Boa => "boa",
+            Engine::QuickJs => "quickjs",
+            Engine::Rhai => "rhai",
+            Engine::V8 => "v8",
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        if cfg!(feature = "boa") {
+            Engine::Boa
+        } else if cfg!(feature = "quickjs") {
+            Engine::QuickJs
+        } else if cfg!(feature = "rusty_v8") || !cfg!(feature = "rhai") {
+            Engine::V8
+        } else {
+            Engine::Rhai
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "boa" => Ok(Engine::Boa),
+            "quickjs" => Ok(Engine::QuickJs),
+            "rhai" => Ok(Engine::Rhai),
+            "v8" => Ok(Engine::V8),
+            _ => Err(anyhow!("Unknown script engine: {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub fn create_engine(
+    engine: Engine,
     env_script: &str,
     env: &str,
     snapshot_script: &str,
-) -> Box<dyn ScriptEngine> {
-    if let Some(engine) = create_script_boa_engine(env_script, env, snapshot_script) {
-        engine
-    } else if let Some(engine) = create_script_v8_engine(env_script, env, snapshot_script) {
-        engine
-    } else {
-        panic!("No Script Engine compiled in the binary");
+    overrides_script: &str,
+    limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    match engine {
+        Engine::Boa => {
+            create_script_boa_engine(env_script, env, snapshot_script, overrides_script, limits)
+        }
+        Engine::QuickJs => {
+            create_script_quickjs_engine(env_script, env, snapshot_script, overrides_script, limits)
+        }
+        Engine::Rhai => {
+            create_script_rhai_engine(env_script, env, snapshot_script, overrides_script, limits)
+        }
+        Engine::V8 => {
+            create_script_v8_engine(env_script, env, snapshot_script, overrides_script, limits)
+        }
     }
 }
 
+fn not_compiled(engine: Engine) -> anyhow::Error {
+    anyhow!(
+        "The {} script engine is not compiled in the binary, rebuild with `--features {}`",
+        engine,
+        engine
+    )
+}
+
 #[cfg(feature = "boa")]
 fn create_script_boa_engine(
     env_script: &str,
     env: &str,
     snapshot_script: &str,
-) -> Option<Box<dyn ScriptEngine>> {
+    overrides_script: &str,
+    limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
     use crate::script_engine::boa::BoaScriptEngine;
-    Some(Box::new(
-        BoaScriptEngine::new(env_script, env, snapshot_script).unwrap(),
-    ))
+    Ok(Box::new(BoaScriptEngine::new(
+        env_script,
+        env,
+        snapshot_script,
+        overrides_script,
+        limits,
+    )?))
 }
 #[cfg(not(feature = "boa"))]
 fn create_script_boa_engine(
     _env_script: &str,
     _env: &str,
     _snapshot_script: &str,
-) -> Option<Box<dyn ScriptEngine>> {
-    None
+    _overrides_script: &str,
+    _limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    Err(not_compiled(Engine::Boa))
+}
+
+#[cfg(feature = "quickjs")]
+fn create_script_quickjs_engine(
+    env_script: &str,
+    env: &str,
+    snapshot_script: &str,
+    overrides_script: &str,
+    limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    use crate::script_engine::quickjs::QuickJsScriptEngine;
+    Ok(Box::new(QuickJsScriptEngine::new(
+        env_script,
+        env,
+        snapshot_script,
+        overrides_script,
+        limits,
+    )?))
+}
+#[cfg(not(feature = "quickjs"))]
+fn create_script_quickjs_engine(
+    _env_script: &str,
+    _env: &str,
+    _snapshot_script: &str,
+    _overrides_script: &str,
+    _limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    Err(not_compiled(Engine::QuickJs))
+}
+
+#[cfg(feature = "rhai")]
+fn create_script_rhai_engine(
+    env_script: &str,
+    env: &str,
+    snapshot_script: &str,
+    overrides_script: &str,
+    limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    use crate::script_engine::rhai::RhaiScriptEngine;
+    Ok(Box::new(RhaiScriptEngine::new(
+        env_script,
+        env,
+        snapshot_script,
+        overrides_script,
+        limits,
+    )?))
+}
+#[cfg(not(feature = "rhai"))]
+fn create_script_rhai_engine(
+    _env_script: &str,
+    _env: &str,
+    _snapshot_script: &str,
+    _overrides_script: &str,
+    _limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    Err(not_compiled(Engine::Rhai))
 }
 
 #[cfg(feature = "rusty_v8")]
@@ -80,19 +233,33 @@ fn create_script_v8_engine(
     env_script: &str,
     env: &str,
     snapshot_script: &str,
-) -> Option<Box<dyn ScriptEngine>> {
+    overrides_script: &str,
+    limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
     use crate::script_engine::v8::V8ScriptEngine;
-    Some(Box::new(
-        V8ScriptEngine::new(env_script, env, snapshot_script).unwrap(),
-    ))
+    Ok(Box::new(V8ScriptEngine::new(
+        env_script,
+        env,
+        snapshot_script,
+        overrides_script,
+        limits,
+    )?))
 }
 #[cfg(not(feature = "rusty_v8"))]
 fn create_script_v8_engine(
     _env_script: &str,
     _env: &str,
     _snapshot_script: &str,
-) -> Option<Box<dyn ScriptEngine>> {
-    None
+    _overrides_script: &str,
+    _limits: &Limits,
+) -> Result<Box<dyn ScriptEngine>> {
+    Err(not_compiled(Engine::V8))
+}
+
+/// Rewrites `$processEnv.NAME` in handler and pre-request scripts as in inline scripts, so reading
+/// a variable that isn't set is an error in both
+pub fn expand_process_env(script: String) -> String {
+    natives::expand_process_env(&script).unwrap_or(script)
 }
 
 pub struct Script<'a> {
@@ -101,24 +268,157 @@ pub struct Script<'a> {
 }
 
 impl<'a> Script<'a> {
-    pub fn internal_script(src: &str) -> Script {
+    pub fn internal_script(src: &str) -> Script<'_> {
         Script {
             src,
             selection: Selection::none(),
         }
     }
+
+    /// Maps a line and column within the script, both starting at 1, to its file
+    #[cfg(any(
+        feature = "boa",
+        feature = "quickjs",
+        feature = "rhai",
+        feature = "rusty_v8"
+    ))]
+    pub fn locate(&self, line: usize, col: usize) -> Selection {
+        let position = crate::parser::Position {
+            line: self.selection.start.line + line - 1,
+            col: if line == 1 {
+                self.selection.start.col + col - 1
+            } else {
+                col
+            },
+        };
+        Selection {
+            filename: self.selection.filename.clone(),
+            start: position.clone(),
+            end: position,
+        }
+    }
+}
+
+/// What a script asks the run to do once it completes
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Control {
+    /// Go on with the request, then with the next one
+    #[default]
+    Continue,
+    /// `client.skip()`, the request isn't sent when asked by its pre-request script
+    Skip,
+    /// `client.exit(status)`, no further request is run
+    Exit(i32),
+    /// `client.goto(name)`, the run goes on with the request named `name`
+    Goto(String),
+}
+
+/// Scripts keep their request as `skip`, `exit <status>` or `goto <name>`, empty when they made none
+impl FromStr for Control {
+    type Err = anyhow::Error;
+
+    fn from_str(control: &str) -> Result<Self> {
+        match control.split_once(' ') {
+            _ if control.is_empty() => Ok(Control::Continue),
+            _ if control == "skip" => Ok(Control::Skip),
+            Some(("exit", status)) => {
+                Ok(Control::Exit(status.parse().map_err(|_| {
+                    anyhow!("Expected an integer exit status, found: {}", status)
+                })?))
+            }
+            Some(("goto", name)) => Ok(Control::Goto(name.to_string())),
+            _ => Err(anyhow!("Unknown control: {}", control)),
+        }
+    }
 }
 
 pub trait ScriptEngine {
     fn execute_script(&mut self, script: &Script) -> Result<String>;
 
-    fn empty(&self) -> String;
+    /// Sets a global to a JSON value through the engine's value API, never through script source
+    fn set_global(&mut self, name: &str, value: &serde_json::Value) -> Result<()>;
+
+    /// Evaluates the prelude once the engine is created, the functions and variables it declares
+    /// stay available to every later script
+    fn load_prelude(&mut self, script: &Script) -> Result<()> {
+        self.execute_script(script)?;
+        Ok(())
+    }
 
-    fn reset(&mut self) -> Result<()>;
+    /// Clears the request scope, called once a request completes
+    fn reset(&mut self) -> Result<()> {
+        self.execute_script(&Script::internal_script("_resetRequest()"))?;
+        Ok(())
+    }
+
+    /// Clears the run scope and any named responses, called before a run starts
+    fn reset_run(&mut self) -> Result<()> {
+        self.execute_script(&Script::internal_script("_resetRun()"))?;
+        Ok(())
+    }
 
     fn snapshot(&mut self) -> Result<String>;
 
-    fn handle(&mut self, script: &Script, response: &crate::Response) -> Result<()>;
+    /// Runs a response handler, returning what it asked the run to do next
+    fn handle(&mut self, script: &Script, response: &crate::Response) -> Result<Control>;
+
+    /// Runs a pre-request script, returning what it asked the run to do next
+    fn pre_request(&mut self, script: &Script) -> Result<Control> {
+        self.execute_script(script)?;
+        self.take_control()
+    }
+
+    /// What the last script asked with `client.skip`, `client.exit` or `client.goto`, the request
+    /// is cleared so that it is only acted on once
+    fn take_control(&mut self) -> Result<Control> {
+        self.execute_script(&Script::internal_script("_takeControl(_control)"))?
+            .parse()
+    }
+
+    /// Exposes the response of a request named with `# @name` as `{name}.response`
+    fn declare_response(&mut self, name: &str, response: &crate::Response) -> Result<()> {
+        let name = serde_json::to_string(name).unwrap();
+        let script = format!(
+            "_responses[{}] = true; this[{}] = {{ response: {} }};",
+            name,
+            name,
+            to_script_value(response)
+        );
+        self.execute_script(&Script::internal_script(&script))?;
+        Ok(())
+    }
+
+    /// Replaces the cookies scripts see in `client.cookies` with a JSON array of cookies
+    fn set_cookies(&mut self, cookies: &str) -> Result<()> {
+        self.set_global("_cookies", &serde_json::from_str(cookies)?)
+    }
+
+    /// The cookies of `client.cookies` as scripts left them, as a JSON array
+    fn cookies(&mut self) -> Result<String> {
+        self.execute_script(&Script::internal_script("JSON.stringify(_cookies)"))
+    }
+
+    /// Evaluates the condition of a `# @retry` directive against a response
+    fn condition(&mut self, script: &Script, response: &crate::Response) -> Result<bool> {
+        let inject = format!("this.response = {};", to_script_value(response));
+        self.execute_script(&Script::internal_script(&inject))?;
+        // Coerced the way `if` would, the selection starts earlier by the width of `!!(` so errors
+        // in the condition keep their column
+        let src = format!("!!({})", script.src);
+        let mut selection = script.selection.clone();
+        selection.start.col = selection.start.col.saturating_sub(3);
+        Ok(self.execute_script(&Script {
+            selection,
+            src: &src,
+        })? == "true")
+    }
+
+    /// Sets request variables, like `request.variables.set` does, until the request completes
+    fn declare_variables(&mut self, variables: &Map<String, serde_json::Value>) -> Result<()> {
+        self.set_global("_variables", &serde_json::Value::Object(variables.clone()))?;
+        self.execute_script(&Script::internal_script("_declareVariables(_variables)"))?;
+        Ok(())
+    }
 
     fn process(&mut self, value: Value<Unprocessed>) -> Result<Value<Processed>> {
         match value {
@@ -133,9 +433,14 @@ pub trait ScriptEngine {
                 let mut interpolated = value;
                 for inline_script in inline_scripts {
                     let placeholder = inline_script.placeholder.clone();
+                    let (format, script) = natives::split_format(&inline_script.script);
+                    let script =
+                        natives::expand_process_env(script).unwrap_or_else(|| script.to_string());
+                    let src = natives::expand_json_path(&script).unwrap_or(script);
+                    let src = natives::format_inline(&src, format);
                     let result = self.execute_script(&Script {
                         selection: inline_script.selection.clone(),
-                        src: &inline_script.script,
+                        src: &src,
                     })?;
                     interpolated = interpolated.replacen(placeholder.as_str(), result.as_str(), 1);
                 }
@@ -160,50 +465,115 @@ struct Response {
     body: Option<String>,
     headers: Map<String, serde_json::Value>,
     status: u16,
+    redirects: Vec<Redirect>,
+}
+
+/// A redirection as scripts see it in `response.redirects`
+#[derive(Deserialize, Serialize)]
+struct Redirect {
+    url: String,
+    status: u16,
+    location: String,
+    headers: Map<String, serde_json::Value>,
+}
+
+fn to_headers(headers: &[(String, String)]) -> Map<String, serde_json::Value> {
+    let mut map = Map::new();
+    for (key, value) in headers {
+        map.insert(key.clone(), serde_json::Value::String(value.clone()));
+    }
+    map
 }
 
 impl From<&crate::Response> for Response {
     fn from(response: &crate::Response) -> Self {
-        let mut headers = Map::new();
-        for (key, value) in response.headers.as_slice() {
-            headers.insert(key.clone(), serde_json::Value::String(value.clone()));
-        }
         Response {
             body: response.body.clone(),
-            headers,
+            headers: to_headers(&response.headers),
             status: response.status_code,
+            redirects: response
+                .redirects
+                .iter()
+                .map(|redirect| Redirect {
+                    url: redirect.url.clone(),
+                    status: redirect.status_code,
+                    location: redirect.location.clone(),
+                    headers: to_headers(&redirect.headers),
+                })
+                .collect(),
         }
     }
 }
 
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rusty_v8"))]
 fn handle(
     engine: &mut dyn ScriptEngine,
     script: &Script,
     response: &crate::Response,
-) -> Result<()> {
+) -> Result<Control> {
     inject(engine, response)?;
     engine.execute_script(script)?;
-    Ok(())
+    engine.take_control()
 }
 
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rusty_v8"))]
 fn inject(engine: &mut dyn ScriptEngine, response: &crate::Response) -> Result<()> {
-    let response: Response = response.into();
-
-    let script = format!(
-        "var response = {};",
-        serde_json::to_string(&response).unwrap()
-    );
+    let script = format!("this.response = {};", to_script_value(response));
     engine.execute_script(&Script::internal_script(&script))?;
-    if let Some(body) = response.body {
+    Ok(())
+}
+
+fn to_script_value(response: &crate::Response) -> serde_json::Value {
+    let response: Response = response.into();
+    let body = response.body.clone();
+    let mut value = serde_json::to_value(response).unwrap();
+    if let Some(body) = body {
         if let Ok(serde_json::Value::Object(response_body)) = serde_json::from_str(body.as_str()) {
-            let script = format!(
-                "response.body = {};",
-                serde_json::to_string(&response_body).unwrap()
-            );
-            engine
-                .execute_script(&Script::internal_script(&script))
-                .unwrap();
+            value["body"] = serde_json::Value::Object(response_body);
         }
     }
-    Ok(())
+    value
+}
+
+/// The globals a JavaScript engine starts with: `$processEnv` and `process.env`, the variables of
+/// the environment, of the snapshot and the overrides given on the command line, then `_env`,
+/// `_snapshot` and `_overrides` that `init.js` looks them up in. Engines set these
+/// through their own value API, keys and values never end up in the source of a script.
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rusty_v8"))]
+fn initial_globals(
+    env_script: &str,
+    env: &str,
+    snapshot_script: &str,
+    overrides_script: &str,
+) -> Result<Vec<(String, serde_json::Value)>> {
+    let variables = |object: serde_json::Value| match object {
+        serde_json::Value::Object(map) => Ok(map),
+        object => Err(anyhow!("Failed to declare object: {:?}", object)),
+    };
+    let mut environment: serde_json::Value = serde_json::from_str(env_script)?;
+    let environment = match environment.get_mut(env) {
+        Some(environment) => variables(environment.take())?,
+        None => serde_json::Map::new(),
+    };
+    let snapshot = variables(serde_json::from_str(snapshot_script)?)?;
+    let overrides = variables(serde_json::from_str(overrides_script)?)?;
+
+    let process_variables = serde_json::Value::Object(natives::process_variables());
+
+    let mut globals = Vec::with_capacity(environment.len() + snapshot.len() + overrides.len() + 5);
+    globals.push(("$processEnv".to_string(), process_variables.clone()));
+    globals.push((
+        "process".to_string(),
+        serde_json::json!({ "env": process_variables }),
+    ));
+    globals.extend(environment.clone());
+    globals.push(("_env".to_string(), serde_json::Value::Object(environment)));
+    globals.extend(snapshot.clone());
+    globals.push(("_snapshot".to_string(), serde_json::Value::Object(snapshot)));
+    globals.extend(overrides.clone());
+    globals.push((
+        "_overrides".to_string(),
+        serde_json::Value::Object(overrides),
+    ));
+    Ok(globals)
 }
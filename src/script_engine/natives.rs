@@ -0,0 +1,638 @@
+use crate::Result;
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde_json::{Map, Value};
+use sha2::Digest;
+use sxd_xpath::Value as XPathValue;
+
+/// Invokes `$register!` once for every native exposed to scripts, pairing the global name it is
+/// registered under with the `Native` variant implementing it.
+macro_rules! for_each_native {
+    ($register:ident, $target:ident) => {
+        $register!($target, "jsonPath", Function(json_path));
+        $register!($target, "xpath", Function(xpath));
+        $register!($target, "_jsonPathValue", Function(json_path_value));
+        $register!($target, "_stringify", Function(stringify));
+        $register!($target, "_interpolate", Function(interpolate));
+        $register!($target, "_processEnv", Function(process_env));
+        $register!($target, "_keys", Function(keys));
+        $register!(
+            $target,
+            "encodeURIComponent",
+            Function(encode_uri_component)
+        );
+        $register!(
+            $target,
+            "decodeURIComponent",
+            Function(decode_uri_component)
+        );
+        $register!($target, "_base64Encode", Function(base64_encode));
+        $register!($target, "_base64Decode", Function(base64_decode));
+        $register!($target, "_cryptoWithTextSecret", Method(with_text_secret));
+        $register!($target, "_cryptoWithHexSecret", Method(with_hex_secret));
+        $register!(
+            $target,
+            "_cryptoWithBase64Secret",
+            Method(with_base64_secret)
+        );
+        $register!($target, "_cryptoUpdateWithText", Method(update_with_text));
+        $register!($target, "_cryptoUpdateWithHex", Method(update_with_hex));
+        $register!(
+            $target,
+            "_cryptoUpdateWithBase64",
+            Method(update_with_base64)
+        );
+        $register!($target, "_cryptoDigest", Method(digest));
+        $register!($target, "_cryptoToHex", Method(to_hex));
+        $register!($target, "_cryptoToBase64", Method(to_base64));
+    };
+}
+
+pub type Fields = Map<String, Value>;
+
+#[derive(Clone, Copy)]
+pub enum Native {
+    /// A plain function, the receiver is ignored
+    Function(fn(&[Value]) -> Result<Value>),
+    /// A method reading and updating the data fields of its receiver, returning `None` hands the
+    /// receiver back to the script so calls can be chained
+    Method(fn(&mut Fields, &[Value]) -> Result<Option<Value>>),
+}
+
+fn string_arg<'a>(args: &'a [Value], index: usize, name: &str) -> Result<&'a str> {
+    match args.get(index) {
+        Some(Value::String(value)) => Ok(value.as_str()),
+        _ => Err(anyhow!("Expected a string for argument '{}'", name)),
+    }
+}
+
+fn select(args: &[Value]) -> Result<Vec<Value>> {
+    let object = args.first().unwrap_or(&Value::Null);
+    let path = string_arg(args, 1, "path")?;
+    let selected = jsonpath_lib::select(object, path)
+        .map_err(|e| anyhow!("Invalid JSONPath '{}': {:?}", path, e))?;
+    Ok(selected.into_iter().cloned().collect())
+}
+
+/// `jsonPath(obj, path)`, every value in `obj` matched by `path`
+pub fn json_path(args: &[Value]) -> Result<Value> {
+    Ok(Value::Array(select(args)?))
+}
+
+/// Backs the `{{name.$.path}}` inline form, a single match is returned as is
+pub fn json_path_value(args: &[Value]) -> Result<Value> {
+    let mut selected = select(args)?;
+    Ok(match selected.len() {
+        0 => Value::Null,
+        1 => selected.remove(0),
+        _ => Value::Array(selected),
+    })
+}
+
+/// `JSON.stringify` without the engine's quirks around arrays
+pub fn stringify(args: &[Value]) -> Result<Value> {
+    let value = args.first().unwrap_or(&Value::Null);
+    Ok(Value::String(value.to_string()))
+}
+
+/// `Object.keys`, which the engines don't all provide
+pub fn keys(args: &[Value]) -> Result<Value> {
+    Ok(match args.first() {
+        Some(Value::Object(map)) => map.keys().cloned().map(Value::String).collect(),
+        _ => Value::Array(vec![]),
+    })
+}
+
+/// `xpath(xml, expression)`, node-sets are returned as the string value of each node
+pub fn xpath(args: &[Value]) -> Result<Value> {
+    let xml = string_arg(args, 0, "xml")?;
+    let expression = string_arg(args, 1, "expression")?;
+    let package =
+        sxd_document::parser::parse(xml).map_err(|e| anyhow!("Invalid XML document: {}", e))?;
+    let document = package.as_document();
+    let value = sxd_xpath::evaluate_xpath(&document, expression)
+        .map_err(|e| anyhow!("Invalid XPath '{}': {}", expression, e))?;
+    Ok(match value {
+        XPathValue::Boolean(value) => Value::Bool(value),
+        XPathValue::Number(value) => serde_json::Number::from_f64(value)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        XPathValue::String(value) => Value::String(value),
+        XPathValue::Nodeset(nodes) => Value::Array(
+            nodes
+                .document_order()
+                .iter()
+                .map(|node| Value::String(node.string_value()))
+                .collect(),
+        ),
+    })
+}
+
+/// Everything but the unreserved characters of `encodeURIComponent`
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'!')
+    .remove(b'~')
+    .remove(b'*')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')');
+
+pub fn encode_uri_component(args: &[Value]) -> Result<Value> {
+    let component = string_arg(args, 0, "component")?;
+    Ok(Value::String(
+        utf8_percent_encode(component, URI_COMPONENT).to_string(),
+    ))
+}
+
+pub fn decode_uri_component(args: &[Value]) -> Result<Value> {
+    let component = string_arg(args, 0, "component")?;
+    let decoded = percent_decode_str(component)
+        .decode_utf8()
+        .map_err(|_| anyhow!("URIError: malformed URI sequence"))?;
+    Ok(Value::String(decoded.to_string()))
+}
+
+pub fn base64_encode(args: &[Value]) -> Result<Value> {
+    let text = string_arg(args, 0, "text")?;
+    let config = if args.get(1) == Some(&Value::Bool(true)) {
+        base64::URL_SAFE
+    } else {
+        base64::STANDARD
+    };
+    Ok(Value::String(base64::encode_config(text, config)))
+}
+
+pub fn base64_decode(args: &[Value]) -> Result<Value> {
+    let text = string_arg(args, 0, "text")?;
+    let decoded = decode_base64(text)?;
+    Ok(Value::String(String::from_utf8(decoded).map_err(|_| {
+        anyhow!("Decoded base64 is not valid UTF-8, use crypto's updateWithBase64 for binary data")
+    })?))
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>> {
+    let config = if text.contains(['-', '_']) {
+        base64::URL_SAFE
+    } else {
+        base64::STANDARD
+    };
+    base64::decode_config(text, config).map_err(|e| anyhow!("Invalid base64: {}", e))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(anyhow!("Invalid hex: {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&hex[index..index + 2], 16)
+                .map_err(|_| anyhow!("Invalid hex: {}", hex))
+        })
+        .collect()
+}
+
+/// The bytes a hasher keeps as a hex string under `field`, so they survive the round trip through
+/// the script engine
+fn field_bytes(this: &Fields, field: &str) -> Result<Vec<u8>> {
+    match this.get(field) {
+        Some(Value::String(hex)) => decode_hex(hex),
+        _ => Ok(vec![]),
+    }
+}
+
+fn set_secret(this: &mut Fields, secret: Vec<u8>) -> Result<Option<Value>> {
+    if this.get("hmac") != Some(&Value::Bool(true)) {
+        return Err(anyhow!("Only crypto.hmac hashers accept a secret"));
+    }
+    this.insert("secret".to_string(), Value::String(encode_hex(&secret)));
+    Ok(None)
+}
+
+pub fn with_text_secret(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let secret = string_arg(args, 0, "secret")?.as_bytes().to_vec();
+    set_secret(this, secret)
+}
+
+pub fn with_hex_secret(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let secret = decode_hex(string_arg(args, 0, "secret")?)?;
+    set_secret(this, secret)
+}
+
+pub fn with_base64_secret(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let secret = decode_base64(string_arg(args, 0, "secret")?)?;
+    set_secret(this, secret)
+}
+
+fn update(this: &mut Fields, bytes: &[u8]) -> Result<Option<Value>> {
+    let mut data = field_bytes(this, "data")?;
+    data.extend_from_slice(bytes);
+    this.insert("data".to_string(), Value::String(encode_hex(&data)));
+    Ok(None)
+}
+
+pub fn update_with_text(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let text = string_arg(args, 0, "text")?;
+    update(this, text.as_bytes())
+}
+
+pub fn update_with_hex(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let bytes = decode_hex(string_arg(args, 0, "hex")?)?;
+    update(this, &bytes)
+}
+
+pub fn update_with_base64(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let bytes = decode_base64(string_arg(args, 0, "base64")?)?;
+    update(this, &bytes)
+}
+
+macro_rules! hash {
+    ($hasher:ty, $secret:expr, $data:expr) => {
+        match $secret {
+            None => <$hasher as Digest>::digest($data).to_vec(),
+            Some(secret) => {
+                let mut mac = <Hmac<$hasher> as Mac>::new_from_slice(secret)
+                    .map_err(|e| anyhow!("Invalid secret: {}", e))?;
+                mac.update($data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    };
+}
+
+pub fn digest(this: &mut Fields, _args: &[Value]) -> Result<Option<Value>> {
+    let data = field_bytes(this, "data")?;
+    let secret = match this.get("hmac") {
+        Some(Value::Bool(true)) => Some(field_bytes(this, "secret")?),
+        _ => None,
+    };
+    let secret = secret.as_deref();
+    let digest = match this.get("algorithm").and_then(Value::as_str) {
+        Some("md5") => hash!(md5::Md5, secret, &data),
+        Some("sha1") => hash!(sha1::Sha1, secret, &data),
+        Some("sha256") => hash!(sha2::Sha256, secret, &data),
+        Some("sha384") => hash!(sha2::Sha384, secret, &data),
+        Some("sha512") => hash!(sha2::Sha512, secret, &data),
+        algorithm => return Err(anyhow!("Unsupported hash algorithm: {:?}", algorithm)),
+    };
+    this.insert("digest".to_string(), Value::String(encode_hex(&digest)));
+    Ok(None)
+}
+
+fn digest_bytes(this: &Fields) -> Result<Vec<u8>> {
+    if !this.contains_key("digest") {
+        return Err(anyhow!("digest() must be called before reading the hash"));
+    }
+    field_bytes(this, "digest")
+}
+
+pub fn to_hex(this: &mut Fields, _args: &[Value]) -> Result<Option<Value>> {
+    Ok(Some(Value::String(encode_hex(&digest_bytes(this)?))))
+}
+
+pub fn to_base64(this: &mut Fields, args: &[Value]) -> Result<Option<Value>> {
+    let config = if args.first() == Some(&Value::Bool(true)) {
+        base64::URL_SAFE
+    } else {
+        base64::STANDARD
+    };
+    Ok(Some(Value::String(base64::encode_config(
+        digest_bytes(this)?,
+        config,
+    ))))
+}
+
+/// How the result of an inline script is written into the request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Strings as they are, anything else as JSON
+    Plain,
+    /// `{{json name}}`, JSON even for strings, which get quoted and escaped
+    Json,
+    /// `{{url name}}`, percent-encoded like `encodeURIComponent`
+    Url,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Json => "json",
+            Format::Url => "url",
+        }
+    }
+}
+
+/// Splits the format off an inline script, `json name` is the JSON of `name`. A format is only
+/// recognised when followed by an expression, so `{{url + path}}` still reads the `url` variable.
+pub fn split_format(script: &str) -> (Format, &str) {
+    for format in &[Format::Json, Format::Url] {
+        if let Some(rest) = script.strip_prefix(format.name()) {
+            let expression = rest.trim_start();
+            let starts_expression = expression
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_alphanumeric() || "_$'\"([".contains(c));
+            if rest.len() > expression.len() && starts_expression {
+                return (*format, expression);
+            }
+        }
+    }
+    (Format::Plain, script)
+}
+
+/// Wraps an inline script so its result is formatted by `_inline`. Only an expression can be
+/// wrapped, so in `{{ var a = 1; a }}` it's what follows the last statement, and a script ending
+/// with a statement is left as it is.
+pub fn format_inline(script: &str, format: Format) -> String {
+    let trimmed = script.trim_end();
+    let trimmed = trimmed.strip_suffix(';').unwrap_or(trimmed);
+    let (statements, expression) = match last_statement_end(trimmed) {
+        Some(end) => trimmed.split_at(end + 1),
+        None => ("", trimmed),
+    };
+    if expression.trim().is_empty() || starts_statement(expression) {
+        return script.to_string();
+    }
+    format!(
+        "{}_inline(({}), \"{}\")",
+        statements,
+        expression,
+        format.name()
+    )
+}
+
+const STATEMENT_KEYWORDS: &[&str] = &[
+    "break", "class", "const", "continue", "do", "fn", "for", "function", "if", "let", "loop",
+    "return", "switch", "throw", "try", "var", "while",
+];
+
+fn starts_statement(script: &str) -> bool {
+    let script = script.trim_start();
+    let length = script
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+        .unwrap_or(script.len());
+    STATEMENT_KEYWORDS.contains(&&script[..length])
+}
+
+/// Where the last statement of a script ends, at its `;`
+fn last_statement_end(script: &str) -> Option<usize> {
+    let mut end = None;
+    scan_code(script, |index, c, depth| {
+        if c == ';' && depth == 0 {
+            end = Some(index);
+        }
+        true
+    });
+    end
+}
+
+/// Walks the code of a script the way a tokenizer would, skipping string and template literals,
+/// but not the substitutions of the latter, and comments. `visit` is called with the index,
+/// character and bracket depth of each character of code, the depth of a bracket being the one of
+/// its pair, until it returns `false`.
+fn scan_code(script: &str, mut visit: impl FnMut(usize, char, isize) -> bool) {
+    let mut depth = 0;
+    // The depths at which `${` substitutions were opened, their template goes on once they close
+    let mut substitutions = vec![];
+    let mut template = false;
+    let mut chars = script.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+        if template {
+            match (c, next) {
+                ('\\', _) => {
+                    chars.next();
+                }
+                ('`', _) => template = false,
+                ('$', Some('{')) => {
+                    chars.next();
+                    substitutions.push(depth);
+                    template = false;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        match (c, next) {
+            ('`', _) => template = true,
+            ('}', _) if substitutions.last() == Some(&depth) => {
+                substitutions.pop();
+                template = true;
+            }
+            ('\'' | '"', _) => {
+                let mut escaped = false;
+                for (_, next) in chars.by_ref() {
+                    match next {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        next if next == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            ('/', Some('/')) => {
+                for (_, next) in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut star = false;
+                for (_, next) in chars.by_ref() {
+                    if star && next == '/' {
+                        break;
+                    }
+                    star = next == '*';
+                }
+            }
+            _ => {
+                let at = match c {
+                    '(' | '[' | '{' => {
+                        depth += 1;
+                        depth - 1
+                    }
+                    ')' | ']' | '}' => {
+                        depth -= 1;
+                        depth
+                    }
+                    _ => depth,
+                };
+                if !visit(index, c, at) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Backs inline scripts, the text written into the request for a value
+pub fn interpolate(args: &[Value]) -> Result<Value> {
+    let value = normalize(args.first().unwrap_or(&Value::Null));
+    let format = match args.get(1) {
+        Some(Value::String(format)) if format == Format::Json.name() => Format::Json,
+        Some(Value::String(format)) if format == Format::Url.name() => Format::Url,
+        _ => Format::Plain,
+    };
+    let text = match (format, value) {
+        (Format::Json, value) => value.to_string(),
+        (_, Value::String(text)) => text,
+        (_, value) => value.to_string(),
+    };
+    Ok(Value::String(match format {
+        Format::Url => utf8_percent_encode(&text, URI_COMPONENT).to_string(),
+        _ => text,
+    }))
+}
+
+/// Engines hand over every number as a float, whole ones are turned back into integers so `42`
+/// isn't written as `42.0`
+fn normalize(value: &Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            Some(float) if number.is_f64() && float.fract() == 0.0 && float.abs() < 9e15 => {
+                Value::from(float as i64)
+            }
+            _ => value.clone(),
+        },
+        Value::Array(values) => Value::Array(values.iter().map(normalize).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), normalize(value)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// The variables of the process, `$processEnv` and `process.env` in scripts. Variables that
+/// aren't valid unicode are left out.
+pub fn process_variables() -> Map<String, Value> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .map(|(name, value)| (name, Value::String(value)))
+        .collect()
+}
+
+/// Backs `$processEnv.NAME` in scripts, a missing variable is an error unless a default is
+/// given
+pub fn process_env(args: &[Value]) -> Result<Value> {
+    let name = string_arg(args, 0, "name")?;
+    match (std::env::var(name), args.get(1)) {
+        (Ok(value), _) => Ok(Value::String(value)),
+        (Err(_), Some(default)) => Ok(default.clone()),
+        (Err(_), None) => Err(anyhow!("The environment variable {} is not set", name)),
+    }
+}
+
+const PROCESS_ENV: &str = "$processEnv.";
+
+/// Rewrites `$processEnv.NAME` in scripts into a call to the process environment native, so a
+/// missing variable is reported rather than read as `undefined`. `$processEnv.NAME ?? default`
+/// hands the default to the native, which keeps it working in engines without `??`. Strings,
+/// template literals and comments are left alone.
+pub fn expand_process_env(script: &str) -> Option<String> {
+    let mut starts = vec![];
+    scan_code(script, |index, _, _| {
+        let identifier = script[..index]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '$');
+        if !identifier && script[index..].starts_with(PROCESS_ENV) {
+            starts.push(index);
+        }
+        true
+    });
+    if starts.is_empty() {
+        return None;
+    }
+    let mut expanded = String::new();
+    let mut consumed = 0;
+    for index in starts {
+        // Within a default, expanded along with it
+        if index < consumed {
+            continue;
+        }
+        let after = &script[index + PROCESS_ENV.len()..];
+        let length = after
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let (name, tail) = after.split_at(length);
+        if name.is_empty() {
+            continue;
+        }
+        expanded.push_str(&script[consumed..index]);
+        let name = serde_json::to_string(name).unwrap();
+        let rest = match tail.trim_start().strip_prefix("??") {
+            Some(default) => {
+                let (default, remaining) = default.split_at(operand_length(default));
+                let default = default.trim();
+                let default = expand_process_env(default).unwrap_or_else(|| default.to_string());
+                expanded.push_str(&format!("_processEnv({}, ({}))", name, default));
+                remaining
+            }
+            None => {
+                expanded.push_str(&format!("_processEnv({})", name));
+                tail
+            }
+        };
+        consumed = script.len() - rest.len();
+    }
+    expanded.push_str(&script[consumed..]);
+    Some(expanded)
+}
+
+/// The length of the operand starting a script, up to a `,` or `;` or a closing bracket that isn't
+/// part of it
+fn operand_length(script: &str) -> usize {
+    let mut length = script.len();
+    scan_code(script, |index, c, depth| match c {
+        ')' | ']' | '}' if depth < 0 => {
+            length = index;
+            false
+        }
+        ',' | ';' if depth == 0 => {
+            length = index;
+            false
+        }
+        _ => true,
+    });
+    length
+}
+
+/// Rewrites the `{{name.$.path}}` inline form into a call to the JSONPath native. A `.$` within a
+/// string, template literal or comment isn't the inline form.
+pub fn expand_json_path(script: &str) -> Option<String> {
+    let mut found = None;
+    scan_code(script, |index, _, _| {
+        let inline = script[index..].starts_with(".$")
+            && matches!(
+                script[index + 2..].chars().next(),
+                None | Some('.') | Some('[')
+            );
+        if inline {
+            found = Some(index);
+        }
+        !inline
+    });
+    let index = found?;
+    let (expression, path) = script.split_at(index);
+    if expression.trim().is_empty() {
+        return None;
+    }
+    Some(format!(
+        "_jsonPathValue({}, {})",
+        expression,
+        serde_json::to_string(&path[1..]).unwrap()
+    ))
+}
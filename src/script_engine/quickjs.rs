@@ -0,0 +1,182 @@
+use crate::script_engine::limits::{self, Budget, Limits};
+use crate::script_engine::natives::{self, Native};
+use crate::script_engine::{handle, initial_globals, Control, Script, ScriptEngine};
+use crate::{Response, Result};
+
+use rquickjs::context::EvalOptions;
+use rquickjs::function::{Rest, This};
+use rquickjs::{Coerced, Context, Ctx, Exception, Function, Runtime, Value};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub struct QuickJsScriptEngine {
+    // The context keeps its own reference to the runtime, this one is kept around to configure it
+    #[allow(dead_code)]
+    runtime: Runtime,
+    context: Context,
+    limits: Limits,
+    /// The budget of the script being run, checked by the interrupt handler
+    budget: Rc<RefCell<Option<Budget>>>,
+}
+
+impl QuickJsScriptEngine {
+    pub fn new(
+        env_script: &str,
+        env: &str,
+        snapshot_script: &str,
+        overrides_script: &str,
+        limits: &Limits,
+    ) -> Result<QuickJsScriptEngine> {
+        let runtime = Runtime::new()?;
+        let budget = Rc::new(RefCell::new(None::<Budget>));
+        let interrupt_budget = budget.clone();
+        runtime.set_interrupt_handler(Some(Box::new(move || {
+            interrupt_budget
+                .borrow_mut()
+                .as_mut()
+                .and_then(Budget::tick)
+                .is_some()
+        })));
+        if let Some(max_heap) = limits.max_heap {
+            runtime.set_memory_limit(max_heap);
+        }
+        let context = Context::full(&runtime)?;
+        context.with(|ctx| -> Result<()> {
+            let target = ctx.globals();
+            macro_rules! register {
+                ($target:ident, $name:literal, $kind:ident($native:ident)) => {{
+                    fn native<'js>(
+                        ctx: Ctx<'js>,
+                        this: This<Value<'js>>,
+                        args: Rest<Value<'js>>,
+                    ) -> rquickjs::Result<Value<'js>> {
+                        call_native(Native::$kind(natives::$native), ctx, this, args)
+                    }
+                    let function = Function::new(ctx.clone(), native)?.with_name($name)?;
+                    $target.set($name, function)?;
+                }};
+            }
+            for_each_native!(register, target);
+
+            for (name, value) in
+                initial_globals(env_script, env, snapshot_script, overrides_script)?
+            {
+                target.set(name, from_json(&ctx, &value)?)?;
+            }
+            Ok(())
+        })?;
+
+        let mut engine = QuickJsScriptEngine {
+            runtime,
+            context,
+            limits: limits.clone(),
+            budget,
+        };
+
+        let script = include_str!("init.js");
+        engine.execute_script(&Script::internal_script(script))?;
+
+        Ok(engine)
+    }
+}
+
+fn call_native<'js>(
+    native: Native,
+    ctx: Ctx<'js>,
+    this: This<Value<'js>>,
+    args: Rest<Value<'js>>,
+) -> rquickjs::Result<Value<'js>> {
+    let This(this) = this;
+    let args = args
+        .0
+        .into_iter()
+        .map(|value| to_json(&ctx, value))
+        .collect::<rquickjs::Result<Vec<serde_json::Value>>>()?;
+    let result = match native {
+        Native::Function(function) => function(&args).map(Some),
+        Native::Method(method) => match (to_json(&ctx, this.clone())?, this.as_object()) {
+            (serde_json::Value::Object(mut fields), Some(object)) => {
+                let result = method(&mut fields, &args);
+                for (key, value) in fields {
+                    object.set(key, from_json(&ctx, &value)?)?;
+                }
+                result
+            }
+            _ => Err(anyhow!("Method called without an object receiver")),
+        },
+    };
+    match result {
+        Ok(Some(result)) => from_json(&ctx, &result),
+        Ok(None) => Ok(this),
+        Err(error) => Err(Exception::throw_message(&ctx, &error.to_string())),
+    }
+}
+
+fn from_json<'js>(ctx: &Ctx<'js>, value: &serde_json::Value) -> rquickjs::Result<Value<'js>> {
+    ctx.json_parse(value.to_string())
+}
+
+fn to_json<'js>(ctx: &Ctx<'js>, value: Value<'js>) -> rquickjs::Result<serde_json::Value> {
+    match ctx.json_stringify(value)? {
+        Some(json) => Ok(serde_json::from_str(&json.to_string()?).unwrap_or_default()),
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+/// Converts the pending exception into an error, errors thrown by scripts stringify to
+/// `{name}: {message}` and are followed by where they were thrown when the script has a file
+fn catch(ctx: &Ctx, script: &Script) -> anyhow::Error {
+    let exception = ctx.catch();
+    let message = match exception.get::<Coerced<String>>() {
+        Ok(Coerced(message)) => message,
+        Err(error) => return anyhow!("Error executing expression: {}", error),
+    };
+    let line = exception.as_exception().and_then(Exception::line);
+    let col = exception.as_exception().and_then(Exception::column);
+    match (line, col) {
+        (Some(line), Some(col))
+            if line > 0 && !script.selection.filename.as_os_str().is_empty() =>
+        {
+            let selection = script.locate(line as usize, col.max(1) as usize);
+            anyhow!("{} at {}", message, selection)
+        }
+        _ => anyhow!("{}", message),
+    }
+}
+
+impl ScriptEngine for QuickJsScriptEngine {
+    fn execute_script(&mut self, script: &Script) -> Result<String> {
+        *self.budget.borrow_mut() = Some(Budget::start(&self.limits));
+        let result = self.context.with(|ctx| {
+            let mut options = EvalOptions::default();
+            options.strict = false;
+            match ctx.eval_with_options::<Coerced<String>, _>(script.src, options) {
+                Ok(Coerced(result)) => Ok(result),
+                Err(rquickjs::Error::Exception) => Err(catch(&ctx, script)),
+                Err(error) => Err(anyhow!("Error executing expression: {}", error)),
+            }
+        });
+        let budget = self.budget.borrow_mut().take();
+        match budget.as_ref().and_then(Budget::exceeded) {
+            Some(exceeded) => Err(limits::exceeded(script, exceeded)),
+            None => result,
+        }
+    }
+
+    fn set_global(&mut self, name: &str, value: &serde_json::Value) -> Result<()> {
+        self.context.with(|ctx| -> Result<()> {
+            ctx.globals().set(name, from_json(&ctx, value)?)?;
+            Ok(())
+        })
+    }
+
+    fn snapshot(&mut self) -> Result<String> {
+        let script = "JSON.stringify(_snapshot)";
+        let out = self.execute_script(&Script::internal_script(script))?;
+        Ok(out)
+    }
+
+    fn handle(&mut self, script: &Script, response: &Response) -> Result<Control> {
+        handle(self, script, response)
+    }
+}
@@ -0,0 +1,499 @@
+use crate::script_engine::limits::{self, Budget, Limits};
+use crate::script_engine::natives::{self, Native};
+use crate::script_engine::{to_script_value, Control, Script, ScriptEngine};
+use crate::{Response, Result};
+
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine as RhaiEngine, EvalAltResult, FnPtr, Scope, AST};
+use serde_json::{json, Map, Value};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The variables that `init.js` keeps in `_env`, `_snapshot`, `_overrides`, `_run` and
+/// `_request`, looked up in reverse order when a script reads a variable it didn't declare itself
+#[derive(Default)]
+struct State {
+    env: Map<String, Value>,
+    snapshot: Map<String, Value>,
+    overrides: Map<String, Value>,
+    run: Map<String, Value>,
+    request: Map<String, Value>,
+    responses: Map<String, Value>,
+    response: Option<Value>,
+    /// What `client.skip`, `client.exit` or `client.goto` asked for last
+    control: Option<Control>,
+    /// The cookies of `client.cookies`, as kept by `init.js` in `_cookies`
+    cookies: Vec<Map<String, Value>>,
+}
+
+impl State {
+    fn lookup(&self, name: &str) -> Option<&Value> {
+        if name == "response" {
+            return self.response.as_ref();
+        }
+        self.request
+            .get(name)
+            .or_else(|| self.run.get(name))
+            .or_else(|| self.overrides.get(name))
+            .or_else(|| self.snapshot.get(name))
+            .or_else(|| self.env.get(name))
+            .or_else(|| self.responses.get(name))
+    }
+}
+
+type SharedState = Rc<RefCell<State>>;
+
+/// `client` in scripts, with `client.global` and `client.run`
+#[derive(Clone)]
+struct Client(SharedState);
+
+impl Client {
+    fn control(&mut self, control: Control) {
+        self.0.borrow_mut().control = Some(control);
+    }
+}
+
+/// `request` in scripts, with `request.variables`
+#[derive(Clone)]
+struct Request(SharedState);
+
+/// `client.cookies` in scripts
+#[derive(Clone)]
+struct Cookies(SharedState);
+
+impl Cookies {
+    fn get(&mut self, name: &str) -> Dynamic {
+        let state = self.0.borrow();
+        let cookie = state.cookies.iter().find(|cookie| cookie["name"] == name);
+        match cookie.and_then(|cookie| cookie["value"].as_str()) {
+            Some(value) => value.into(),
+            None => Dynamic::UNIT,
+        }
+    }
+
+    fn set(&mut self, name: &str, value: Dynamic, domain: Option<String>) {
+        let mut state = self.0.borrow_mut();
+        let index = match state
+            .cookies
+            .iter()
+            .position(|cookie| cookie["name"] == name)
+        {
+            Some(index) => index,
+            None => {
+                let mut cookie = Map::new();
+                cookie.insert("name".to_string(), json!(name));
+                cookie.insert("path".to_string(), json!("/"));
+                state.cookies.push(cookie);
+                state.cookies.len() - 1
+            }
+        };
+        let cookie = &mut state.cookies[index];
+        cookie.insert("value".to_string(), json!(value.to_string()));
+        if let Some(domain) = domain {
+            cookie.insert("domain".to_string(), json!(domain));
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.0
+            .borrow_mut()
+            .cookies
+            .retain(|cookie| cookie["name"] != name);
+    }
+
+    fn all(&mut self) -> std::result::Result<Dynamic, Box<EvalAltResult>> {
+        to_dynamic(&self.0.borrow().cookies)
+    }
+}
+
+#[derive(Clone)]
+struct Variables {
+    state: SharedState,
+    scope: VariableScope,
+}
+
+#[derive(Clone, Copy)]
+enum VariableScope {
+    Global,
+    Run,
+    Request,
+}
+
+impl Variables {
+    fn set(&mut self, key: &str, value: Dynamic) -> std::result::Result<(), Box<EvalAltResult>> {
+        let value: Value = from_dynamic(&value)?;
+        let mut state = self.state.borrow_mut();
+        let variables = match self.scope {
+            VariableScope::Global => &mut state.snapshot,
+            VariableScope::Run => &mut state.run,
+            VariableScope::Request => &mut state.request,
+        };
+        variables.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: &str) -> std::result::Result<Dynamic, Box<EvalAltResult>> {
+        let state = self.state.borrow();
+        let value = match self.scope {
+            VariableScope::Global => state
+                .overrides
+                .get(key)
+                .or_else(|| state.snapshot.get(key))
+                .or_else(|| state.env.get(key)),
+            VariableScope::Run => state.run.get(key),
+            VariableScope::Request => state.request.get(key),
+        };
+        match value {
+            Some(value) => to_dynamic(value),
+            None => Ok(Dynamic::UNIT),
+        }
+    }
+}
+
+/// `crypto` and `crypto.hmac` in scripts, each algorithm returns a hasher map that the
+/// `_crypto*` natives operate on as methods
+#[derive(Clone)]
+struct Crypto {
+    hmac: bool,
+}
+
+/// `Base64` in scripts
+#[derive(Clone)]
+struct Base64;
+
+pub struct RhaiScriptEngine {
+    engine: RhaiEngine,
+    scope: Scope<'static>,
+    state: SharedState,
+    /// The functions declared by the prelude, scripts only see the functions they are compiled
+    /// with while their variables live on in the scope
+    prelude: AST,
+    limits: Limits,
+    /// The budget of the script being run, checked on every operation
+    budget: Rc<RefCell<Option<Budget>>>,
+}
+
+impl RhaiScriptEngine {
+    pub fn new(
+        env_script: &str,
+        env: &str,
+        snapshot_script: &str,
+        overrides_script: &str,
+        limits: &Limits,
+    ) -> Result<RhaiScriptEngine> {
+        let state = SharedState::default();
+        let mut engine = RhaiEngine::new();
+
+        let budget = Rc::new(RefCell::new(None::<Budget>));
+        let progress_budget = budget.clone();
+        engine.on_progress(move |_| {
+            progress_budget
+                .borrow_mut()
+                .as_mut()
+                .and_then(Budget::tick)
+                .map(|exceeded| exceeded.to_string().into())
+        });
+
+        macro_rules! register {
+            ($engine:ident, $name:literal, $kind:ident($native:ident)) => {{
+                for arity in 0..=3 {
+                    register_native(&mut $engine, $name, arity, Native::$kind(natives::$native));
+                }
+            }};
+        }
+        for_each_native!(register, engine);
+        // Inline scripts evaluating to `()` are written as nothing, like undefined in JavaScript
+        engine.register_raw_fn("_inline", vec![TypeId::of::<Dynamic>(); 2], |_, args| {
+            if args[0].is::<()>() {
+                return Ok(Dynamic::from(String::new()));
+            }
+            call_function(
+                natives::interpolate,
+                args.iter().map(|arg| (*arg).clone()).collect(),
+            )
+        });
+
+        engine
+            .register_type_with_name::<Client>("Client")
+            .register_get("global", |client: &mut Client| Variables {
+                state: client.0.clone(),
+                scope: VariableScope::Global,
+            })
+            .register_get("run", |client: &mut Client| Variables {
+                state: client.0.clone(),
+                scope: VariableScope::Run,
+            })
+            .register_get("cookies", |client: &mut Client| Cookies(client.0.clone()))
+            .register_fn("skip", |client: &mut Client| client.control(Control::Skip))
+            .register_fn("exit", |client: &mut Client| {
+                client.control(Control::Exit(0))
+            })
+            .register_fn("exit", |client: &mut Client, status: i64| {
+                client.control(Control::Exit(status as i32))
+            })
+            // `goto` is a reserved keyword of Rhai
+            .register_fn("go_to", |client: &mut Client, name: &str| {
+                client.control(Control::Goto(name.to_string()))
+            })
+            .register_type_with_name::<Cookies>("Cookies")
+            .register_fn("get", Cookies::get)
+            .register_fn(
+                "set",
+                |cookies: &mut Cookies, name: &str, value: Dynamic| cookies.set(name, value, None),
+            )
+            .register_fn(
+                "set",
+                |cookies: &mut Cookies, name: &str, value: Dynamic, domain: &str| {
+                    cookies.set(name, value, Some(domain.to_string()))
+                },
+            )
+            .register_fn("remove", Cookies::remove)
+            .register_fn("clear", |cookies: &mut Cookies| {
+                cookies.0.borrow_mut().cookies.clear()
+            })
+            .register_fn("all", Cookies::all)
+            .register_type_with_name::<Request>("Request")
+            .register_get("variables", |request: &mut Request| Variables {
+                state: request.0.clone(),
+                scope: VariableScope::Request,
+            })
+            .register_type_with_name::<Variables>("Variables")
+            .register_fn("set", Variables::set)
+            .register_fn("get", Variables::get)
+            .register_type_with_name::<Crypto>("Crypto")
+            .register_get("hmac", |_: &mut Crypto| Crypto { hmac: true })
+            .register_type_with_name::<Base64>("Base64")
+            .register_fn("encode", |_: &mut Base64, text: Dynamic| {
+                call_function(natives::base64_encode, vec![text])
+            })
+            .register_fn("decode", |_: &mut Base64, text: Dynamic| {
+                call_function(natives::base64_decode, vec![text])
+            });
+        for algorithm in &["md5", "sha1", "sha256", "sha384", "sha512"] {
+            engine.register_fn(*algorithm, move |crypto: &mut Crypto| {
+                let mut hasher = rhai::Map::new();
+                hasher.insert("algorithm".into(), (*algorithm).into());
+                hasher.insert("hmac".into(), crypto.hmac.into());
+                hasher
+            });
+        }
+
+        let resolver_state = state.clone();
+        #[allow(deprecated)]
+        engine.on_var(move |name, _, context| {
+            if context.scope().contains(name) {
+                return Ok(None);
+            }
+            match resolver_state.borrow().lookup(name) {
+                Some(value) => to_dynamic(value).map(Some),
+                None => Ok(None),
+            }
+        });
+
+        let environment: Value = serde_json::from_str(env_script)?;
+        let snapshot: Value = serde_json::from_str(snapshot_script)?;
+        let overrides: Value = serde_json::from_str(overrides_script)?;
+        {
+            let mut state = state.borrow_mut();
+            if let Some(environment) = environment.get(env) {
+                state.env = variables(environment)?;
+            }
+            state.snapshot = variables(&snapshot)?;
+            state.overrides = variables(&overrides)?;
+        }
+
+        let mut scope = Scope::new();
+        scope.push_constant("client", Client(state.clone()));
+        scope.push_constant("request", Request(state.clone()));
+        scope.push_constant("crypto", Crypto { hmac: false });
+        scope.push_constant("Base64", Base64);
+        let process_variables = Value::Object(natives::process_variables());
+        let process = to_dynamic(json!({ "env": process_variables }))
+            .map_err(|err| anyhow!("Failed to declare process.env: {}", err))?;
+        scope.push_constant("process", process);
+
+        Ok(RhaiScriptEngine {
+            engine,
+            scope,
+            state,
+            prelude: AST::empty(),
+            limits: limits.clone(),
+            budget,
+        })
+    }
+}
+
+fn variables(variables_object: &Value) -> Result<Map<String, Value>> {
+    match variables_object {
+        Value::Object(map) => Ok(map.clone()),
+        _ => Err(anyhow!("Failed to declare object: {:?}", variables_object)),
+    }
+}
+
+/// Registers a native for a given number of arguments, methods are registered under their name
+/// in `init.js`, so `_cryptoToHex` becomes `hasher.toHex()`
+fn register_native(engine: &mut RhaiEngine, name: &'static str, arity: usize, native: Native) {
+    let arg_types = vec![TypeId::of::<Dynamic>(); arity];
+    match native {
+        Native::Function(function) => {
+            engine.register_raw_fn(name, arg_types, move |_, args| {
+                call_function(function, args.iter().map(|arg| (*arg).clone()).collect())
+            });
+        }
+        Native::Method(method) if arity > 0 => {
+            let name = name.trim_start_matches("_crypto");
+            let name = name[..1].to_lowercase() + &name[1..];
+            engine.register_raw_fn(name, arg_types, move |_, args| {
+                let (this, args) = args.split_first_mut().unwrap();
+                let args = to_json(args.iter().map(|arg| (*arg).clone()))?;
+                let mut fields = match from_dynamic(this)? {
+                    Value::Object(fields) => fields,
+                    _ => return Err("Method called without an object receiver".into()),
+                };
+                let result = method(&mut fields, &args).map_err(|e| e.to_string());
+                **this = to_dynamic(Value::Object(fields))?;
+                match result? {
+                    Some(result) => to_dynamic(result),
+                    None => Ok((**this).clone()),
+                }
+            });
+        }
+        Native::Method(_) => {}
+    }
+}
+
+fn call_function(
+    function: fn(&[Value]) -> Result<Value>,
+    args: Vec<Dynamic>,
+) -> std::result::Result<Dynamic, Box<EvalAltResult>> {
+    let args = to_json(args.into_iter())?;
+    let result = function(&args).map_err(|e| e.to_string())?;
+    to_dynamic(result)
+}
+
+fn to_json(
+    args: impl Iterator<Item = Dynamic>,
+) -> std::result::Result<Vec<Value>, Box<EvalAltResult>> {
+    args.map(|arg| {
+        if arg.is::<FnPtr>() {
+            Ok(Value::Null)
+        } else {
+            from_dynamic(&arg)
+        }
+    })
+    .collect()
+}
+
+impl RhaiScriptEngine {
+    /// Compiles and runs a script along with the functions of the prelude
+    fn run(&mut self, script: &Script) -> Result<(AST, Dynamic)> {
+        *self.budget.borrow_mut() = Some(Budget::start(&self.limits));
+        let result = self
+            .engine
+            .compile(script.src)
+            .map_err(Box::<EvalAltResult>::from)
+            .and_then(|ast| {
+                let result = self
+                    .engine
+                    .eval_ast_with_scope::<Dynamic>(&mut self.scope, &self.prelude.merge(&ast))?;
+                Ok((ast, result))
+            });
+        let budget = self.budget.borrow_mut().take();
+        if let Some(exceeded) = budget.as_ref().and_then(Budget::exceeded) {
+            return Err(limits::exceeded(script, exceeded));
+        }
+        result.map_err(|err| {
+            let position = err.position();
+            match position.line() {
+                Some(line) if !script.selection.filename.as_os_str().is_empty() => {
+                    let selection = script.locate(line, position.position().unwrap_or(1));
+                    anyhow!("Error executing expression: {} at {}", err, selection)
+                }
+                _ => anyhow!("Error executing expression: {}", err),
+            }
+        })
+    }
+}
+
+impl ScriptEngine for RhaiScriptEngine {
+    fn execute_script(&mut self, script: &Script) -> Result<String> {
+        let (_, result) = self.run(script)?;
+        Ok(result.to_string())
+    }
+
+    fn load_prelude(&mut self, script: &Script) -> Result<()> {
+        let (ast, _) = self.run(script)?;
+        self.prelude = self.prelude.merge(&ast.clone_functions_only());
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        state.request.clear();
+        state.response = None;
+        Ok(())
+    }
+
+    fn reset_run(&mut self) -> Result<()> {
+        self.reset()?;
+        let mut state = self.state.borrow_mut();
+        state.run.clear();
+        state.responses.clear();
+        Ok(())
+    }
+
+    fn set_global(&mut self, name: &str, value: &Value) -> Result<()> {
+        let value =
+            to_dynamic(value).map_err(|err| anyhow!("Failed to declare {}: {}", name, err))?;
+        self.scope.set_value(name.to_string(), value);
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Result<String> {
+        Ok(serde_json::to_string(&self.state.borrow().snapshot)?)
+    }
+
+    fn set_cookies(&mut self, cookies: &str) -> Result<()> {
+        self.state.borrow_mut().cookies = serde_json::from_str(cookies)?;
+        Ok(())
+    }
+
+    fn cookies(&mut self) -> Result<String> {
+        Ok(serde_json::to_string(&self.state.borrow().cookies)?)
+    }
+
+    fn handle(&mut self, script: &Script, response: &Response) -> Result<Control> {
+        self.state.borrow_mut().response = Some(to_script_value(response));
+        self.execute_script(script)?;
+        self.take_control()
+    }
+
+    fn take_control(&mut self) -> Result<Control> {
+        Ok(self.state.borrow_mut().control.take().unwrap_or_default())
+    }
+
+    fn condition(&mut self, script: &Script, response: &Response) -> Result<bool> {
+        self.state.borrow_mut().response = Some(to_script_value(response));
+        Ok(self.execute_script(script)? == "true")
+    }
+
+    fn declare_response(&mut self, name: &str, response: &Response) -> Result<()> {
+        let mut named = Map::new();
+        named.insert("response".to_string(), to_script_value(response));
+        self.state
+            .borrow_mut()
+            .responses
+            .insert(name.to_string(), Value::Object(named));
+        Ok(())
+    }
+
+    fn declare_variables(&mut self, variables: &Map<String, Value>) -> Result<()> {
+        self.state.borrow_mut().request.extend(
+            variables
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        Ok(())
+    }
+}
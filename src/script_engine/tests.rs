@@ -1,5 +1,30 @@
-use crate::script_engine::{create_script_engine, inject, Script, ScriptEngine};
+use crate::parser::{Position, Selection};
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rusty_v8"))]
+use crate::script_engine::inject;
+use crate::script_engine::natives::{expand_json_path, expand_process_env, split_format, Format};
+use crate::script_engine::{
+    create_engine, Control, Engine, InlineScript, Limits, Script, ScriptEngine, Unprocessed, Value,
+};
 use crate::{Response, Version};
+use std::time::Duration;
+
+/// Runs the suite against the default engine, build with `--no-default-features --features
+/// quickjs` to run it against QuickJS
+fn create_script_engine(
+    env_script: &str,
+    env: &str,
+    snapshot_script: &str,
+) -> Box<dyn ScriptEngine> {
+    create_engine(
+        Engine::default(),
+        env_script,
+        env,
+        snapshot_script,
+        "{}",
+        &Limits::default(),
+    )
+    .unwrap()
+}
 
 #[cfg(test)]
 fn setup(src: &'static str) -> Box<dyn ScriptEngine> {
@@ -67,6 +92,102 @@ fn test_initialize() {
     }
 }
 
+#[test]
+fn test_initialize_hostile_keys() {
+    let env_script = r#"{"dev": {
+        "it's": "quoted",
+        "'];throw 'injected';//": "code",
+        "ключ": "значение",
+        "emoji": "🎉",
+        "separators": "a\u2028b\u2029c"
+    }}"#;
+    let mut engine = create_script_engine(env_script, "dev", "{}");
+
+    let mut run = |src: &str| {
+        engine
+            .execute_script(&Script::internal_script(src))
+            .unwrap()
+    };
+
+    assert_eq!(run(r#"this["it's"]"#), "quoted");
+    assert_eq!(run(r#"_env["'];throw 'injected';//"]"#), "code");
+    assert_eq!(run(r#"this["ключ"]"#), "значение");
+    assert_eq!(run("emoji"), "🎉");
+    assert_eq!(run("separators"), "a\u{2028}b\u{2029}c");
+}
+
+#[test]
+fn test_declare_hostile_variables() {
+    let mut engine = setup("{}");
+    let variables = serde_json::json!({
+        "it's": "quoted",
+        "name": "'); throw 'injected'; ('",
+        "row": {"id": 7, "tags": ["a", "b"]}
+    });
+
+    engine
+        .declare_variables(variables.as_object().unwrap())
+        .unwrap();
+
+    let mut run = |src: &str| {
+        engine
+            .execute_script(&Script::internal_script(src))
+            .unwrap()
+    };
+    assert_eq!(run(r#"request.variables.get("it's")"#), "quoted");
+    assert_eq!(run("name"), "'); throw 'injected'; ('");
+    assert_eq!(run("row.tags[1]"), "b");
+
+    engine.reset().unwrap();
+    let variables = serde_json::json!({ "name": "second" });
+    engine
+        .declare_variables(variables.as_object().unwrap())
+        .unwrap();
+    let name = engine.execute_script(&Script::internal_script("name"));
+    assert_eq!(name.unwrap(), "second");
+}
+
+#[test]
+fn test_initialize_hostile_env_name() {
+    let env = "dev'];throw 'injected';//";
+    let env_script = serde_json::json!({ env: { "host": "example.com" } }).to_string();
+    let mut engine = create_script_engine(&env_script, env, "{}");
+
+    let result = engine.execute_script(&Script::internal_script("host"));
+
+    assert_eq!(result.unwrap(), "example.com");
+}
+
+#[test]
+fn test_initialize_hostile_snapshot() {
+    let snapshot = r#"{"a\"b":"c'd","list":[1,"two",{"three":3}]}"#;
+    let mut engine = create_script_engine("{}", "dev", snapshot);
+
+    let length = engine.execute_script(&Script::internal_script("list.length"));
+
+    assert_eq!(length.unwrap(), "3");
+    let result: serde_json::Value = serde_json::from_str(&engine.snapshot().unwrap()).unwrap();
+    assert_eq!(result["a\"b"], "c'd");
+}
+
+#[test]
+fn test_initialize_large_env() {
+    let variables = (0..5000)
+        .map(|index| {
+            (
+                format!("variable{}", index),
+                serde_json::json!({ "index": index }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let env_script = serde_json::json!({ "dev": variables }).to_string();
+    let mut engine = create_script_engine(&env_script, "dev", "{}");
+
+    let result = engine.execute_script(&Script::internal_script("variable4999.index"));
+
+    assert_eq!(result.unwrap(), "4999");
+}
+
 #[test]
 fn test_reset() {
     let mut engine = create_script_engine(r#"{"dev": {"a": 1}}"#, "dev", "{}");
@@ -87,6 +208,7 @@ fn test_reset() {
     }
 }
 
+#[cfg(any(feature = "boa", feature = "quickjs", feature = "rusty_v8"))]
 #[test]
 fn test_headers_available_in_response() {
     let mut engine = create_script_engine("{}", "dev", "{}");
@@ -99,6 +221,8 @@ fn test_headers_available_in_response() {
         body: Some("{}".to_string()),
         status_code: 0,
         status: "".to_string(),
+        redirects: vec![],
+        proxy: None,
     };
 
     inject(engine.as_mut(), &response).unwrap();
@@ -109,3 +233,1038 @@ fn test_headers_available_in_response() {
 
     assert_eq!("SomeTokenValue", result);
 }
+
+#[test]
+fn test_json_path() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"
+            var body = {items: [{id: 1, active: true}, {id: 2, active: false}, {id: 3, active: true}]};
+            jsonPath(body, '$.items[?(@.active == true)].id').join(',')
+            "#,
+        ))
+        .unwrap();
+
+    assert_eq!("1,3", result);
+}
+
+#[test]
+fn test_json_path_invalid() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine.execute_script(&Script::internal_script("jsonPath({}, '$.[')"));
+
+    assert!(result.is_err(), "Should've been an error");
+}
+
+#[test]
+fn test_xpath() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"xpath('<users><user>alice</user><user>bob</user></users>', '//user')[1]"#,
+        ))
+        .unwrap();
+    assert_eq!("bob", result);
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"xpath('<users><user>alice</user><user>bob</user></users>', 'count(//user)')"#,
+        ))
+        .unwrap();
+    assert_eq!("2", result);
+}
+
+#[test]
+fn test_inline_json_path() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let response = Response {
+        version: Version::Http11,
+        headers: vec![],
+        body: Some(r#"{"token": "SomeToken", "ids": [1, 2]}"#.to_string()),
+        status_code: 200,
+        status: "".to_string(),
+        redirects: vec![],
+        proxy: None,
+    };
+    engine.declare_response("login", &response).unwrap();
+
+    let value = engine
+        .process(Value {
+            state: Unprocessed::WithInline {
+                value: "Bearer {{login.response.body.$.token}}".to_string(),
+                inline_scripts: vec![InlineScript {
+                    script: "login.response.body.$.token".to_string(),
+                    placeholder: "{{login.response.body.$.token}}".to_string(),
+                    selection: Selection::none(),
+                }],
+                selection: Selection::none(),
+            },
+        })
+        .unwrap();
+
+    assert_eq!("Bearer SomeToken", value.state.value);
+    assert_eq!("price.$", interpolate(engine.as_mut(), "'price.$'"));
+}
+
+fn interpolate(engine: &mut dyn ScriptEngine, script: &str) -> String {
+    engine
+        .process(Value {
+            state: Unprocessed::WithInline {
+                value: format!("{{{{{}}}}}", script),
+                inline_scripts: vec![InlineScript {
+                    script: script.to_string(),
+                    placeholder: format!("{{{{{}}}}}", script),
+                    selection: Selection::none(),
+                }],
+                selection: Selection::none(),
+            },
+        })
+        .unwrap()
+        .state
+        .value
+}
+
+#[test]
+fn test_inline_values() {
+    let snapshot = r#"{"user": {"name": "Ann", "ids": [1, 2]}, "count": 42, "name": "Ann \"A\""}"#;
+    let mut engine = create_script_engine("{}", "dev", snapshot);
+
+    let user: serde_json::Value = serde_json::from_str(&interpolate(&mut *engine, "user")).unwrap();
+    assert_eq!(user, serde_json::json!({"name": "Ann", "ids": [1, 2]}));
+    assert_eq!(interpolate(&mut *engine, "user.ids"), "[1,2]");
+    assert_eq!(interpolate(&mut *engine, "count"), "42");
+    assert_eq!(interpolate(&mut *engine, "count / 4"), "10.5");
+    assert_eq!(interpolate(&mut *engine, "name"), r#"Ann "A""#);
+    assert_eq!(interpolate(&mut *engine, "json name"), r#""Ann \"A\"""#);
+    assert_eq!(interpolate(&mut *engine, "json count"), "42");
+    assert_eq!(interpolate(&mut *engine, "json user.ids"), "[1,2]");
+    assert_eq!(interpolate(&mut *engine, "url name"), "Ann%20%22A%22");
+    assert_eq!(interpolate(&mut *engine, "url 'a&b=c'"), "a%26b%3Dc");
+}
+
+#[test]
+fn test_inline_statements() {
+    let snapshot = r#"{"user": {"name": "Ann"}}"#;
+    let mut engine = create_script_engine("{}", "dev", snapshot);
+
+    assert_eq!(interpolate(&mut *engine, "var a = 1; a"), "1");
+    assert_eq!(
+        interpolate(&mut *engine, "var b = {id: 2}; b;"),
+        r#"{"id":2}"#
+    );
+    assert_eq!(interpolate(&mut *engine, "json 'a;b'"), r#""a;b""#);
+    assert_eq!(interpolate(&mut *engine, "user.missing"), "");
+    assert_eq!(interpolate(&mut *engine, "undefined"), "");
+    assert_eq!(interpolate(&mut *engine, "null"), "null");
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_inline_values() {
+    let snapshot = r#"{"user": {"ids": [1, 2]}, "name": "Ann"}"#;
+    let mut engine = create_engine(
+        Engine::Rhai,
+        "{}",
+        "dev",
+        snapshot,
+        "{}",
+        &Limits::default(),
+    )
+    .unwrap();
+
+    assert_eq!(interpolate(&mut *engine, "user"), r#"{"ids":[1,2]}"#);
+    assert_eq!(interpolate(&mut *engine, "json name"), r#""Ann""#);
+    assert_eq!(interpolate(&mut *engine, "url name + \" B\""), "Ann%20B");
+    assert_eq!(interpolate(&mut *engine, "let a = 1; a + 1"), "2");
+    assert_eq!(interpolate(&mut *engine, "()"), "");
+}
+
+#[test]
+fn test_process_env() {
+    std::env::set_var("DOT_HTTP_TEST_TOKEN", "from process");
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    assert_eq!(
+        interpolate(&mut *engine, "$processEnv.DOT_HTTP_TEST_TOKEN"),
+        "from process"
+    );
+    assert_eq!(
+        interpolate(&mut *engine, "process.env.DOT_HTTP_TEST_TOKEN"),
+        "from process"
+    );
+    assert_eq!(
+        interpolate(
+            &mut *engine,
+            "$processEnv.DOT_HTTP_TEST_MISSING ?? 'fall' + 'back'"
+        ),
+        "fallback"
+    );
+    let result = engine.execute_script(&Script::internal_script("$processEnv.DOT_HTTP_TEST_TOKEN"));
+    assert_eq!(result.unwrap(), "from process");
+}
+
+#[test]
+fn test_process_env_missing() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine.process(Value {
+        state: Unprocessed::WithInline {
+            value: "{{$processEnv.DOT_HTTP_TEST_MISSING}}".to_string(),
+            inline_scripts: vec![InlineScript {
+                script: "$processEnv.DOT_HTTP_TEST_MISSING".to_string(),
+                placeholder: "{{$processEnv.DOT_HTTP_TEST_MISSING}}".to_string(),
+                selection: Selection::none(),
+            }],
+            selection: Selection::none(),
+        },
+    });
+
+    let error = result.unwrap_err().to_string();
+    assert!(
+        error.contains("The environment variable DOT_HTTP_TEST_MISSING is not set"),
+        "{}",
+        error
+    );
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_process_env() {
+    std::env::set_var("DOT_HTTP_TEST_RHAI", "rhai");
+    let mut engine =
+        create_engine(Engine::Rhai, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+
+    assert_eq!(
+        interpolate(&mut *engine, "$processEnv.DOT_HTTP_TEST_RHAI"),
+        "rhai"
+    );
+    assert_eq!(
+        interpolate(
+            &mut *engine,
+            "$processEnv.DOT_HTTP_TEST_MISSING ?? \"fallback\""
+        ),
+        "fallback"
+    );
+    assert_eq!(
+        interpolate(&mut *engine, "process.env.DOT_HTTP_TEST_RHAI"),
+        "rhai"
+    );
+}
+
+#[test]
+fn test_expand_process_env() {
+    assert_eq!(
+        expand_process_env("'Bearer ' + $processEnv.TOKEN").unwrap(),
+        r#"'Bearer ' + _processEnv("TOKEN")"#
+    );
+    assert_eq!(
+        expand_process_env("$processEnv.TOKEN ?? 'none'").unwrap(),
+        r#"_processEnv("TOKEN", ('none'))"#
+    );
+    assert_eq!(
+        expand_process_env("f($processEnv.A ?? g(1, ')'), $processEnv.B)").unwrap(),
+        r#"f(_processEnv("A", (g(1, ')'))), _processEnv("B"))"#
+    );
+    assert_eq!(
+        expand_process_env("$processEnv.A ?? $processEnv.B").unwrap(),
+        r#"_processEnv("A", (_processEnv("B")))"#
+    );
+    assert_eq!(
+        expand_process_env("'$processEnv.A' + `${$processEnv.B}` + $processEnv.C // $processEnv.D")
+            .unwrap(),
+        r#"'$processEnv.A' + `${_processEnv("B")}` + _processEnv("C") // $processEnv.D"#
+    );
+    assert_eq!(expand_process_env("\"$processEnv.TOKEN\""), None);
+    assert_eq!(expand_process_env("`$processEnv.A ${'}'}`"), None);
+    assert_eq!(expand_process_env("my$processEnv.TOKEN"), None);
+    assert_eq!(expand_process_env("process.env.TOKEN"), None);
+}
+
+#[test]
+fn test_expand_json_path() {
+    assert_eq!(
+        expand_json_path("login.response.body.$.token").unwrap(),
+        r#"_jsonPathValue(login.response.body, "$.token")"#
+    );
+    assert_eq!(
+        expand_json_path("body['a.$'].$[0]").unwrap(),
+        r#"_jsonPathValue(body['a.$'], "$[0]")"#
+    );
+    assert_eq!(expand_json_path("'price.$'"), None);
+    assert_eq!(expand_json_path("\"price.$.total\""), None);
+    assert_eq!(expand_json_path("`price.$`"), None);
+    assert_eq!(expand_json_path("price // body.$"), None);
+}
+
+#[test]
+fn test_split_format() {
+    assert_eq!(split_format("json name"), (Format::Json, "name"));
+    assert_eq!(split_format("url  'a b'"), (Format::Url, "'a b'"));
+    assert_eq!(split_format("url + path"), (Format::Plain, "url + path"));
+    assert_eq!(split_format("json"), (Format::Plain, "json"));
+    assert_eq!(split_format("jsonBody"), (Format::Plain, "jsonBody"));
+}
+
+#[test]
+fn test_crypto() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "crypto.sha256().updateWithText('abc').digest().toHex()",
+        ))
+        .unwrap();
+    assert_eq!(
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        result
+    );
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "crypto.md5().updateWithText('a').updateWithHex('6263').digest().toHex()",
+        ))
+        .unwrap();
+    assert_eq!("900150983cd24fb0d6963f7d28e17f72", result);
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"crypto.hmac.sha256()
+                .withTextSecret('key')
+                .updateWithText('The quick brown fox jumps over the lazy dog')
+                .digest()
+                .toHex()"#,
+        ))
+        .unwrap();
+    assert_eq!(
+        "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+        result
+    );
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "crypto.hmac.sha1().withBase64Secret('a2V5').updateWithText('').digest().toBase64()",
+        ))
+        .unwrap();
+    assert_eq!("9Cuw7rAY671Fl65yE3EexgdghD8=", result);
+}
+
+#[test]
+fn test_crypto_errors() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine.execute_script(&Script::internal_script(
+        "crypto.sha256().withTextSecret('key')",
+    ));
+    assert!(result.is_err(), "Should've been an error");
+
+    let result = engine.execute_script(&Script::internal_script(
+        "crypto.sha256().updateWithText('abc').toHex()",
+    ));
+    assert!(result.is_err(), "Should've been an error");
+}
+
+#[test]
+fn test_encoding() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "Base64.decode(Base64.encode('user:pässword')) + ' ' + Base64.encode('user:pass')",
+        ))
+        .unwrap();
+    assert_eq!("user:pässword dXNlcjpwYXNz", result);
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "encodeURIComponent('a b&c=d/é') + ' ' + decodeURIComponent('a%20b%26')",
+        ))
+        .unwrap();
+    assert_eq!("a%20b%26c%3Dd%2F%C3%A9 a b&", result);
+}
+
+#[test]
+fn test_request_variables() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+    engine
+        .execute_script(&Script::internal_script(
+            r#"request.variables.set("nonce", "abc")"#,
+        ))
+        .unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"nonce + request.variables.get("nonce")"#,
+        ))
+        .unwrap();
+    assert_eq!("abcabc", result);
+
+    engine.reset().unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"typeof nonce + request.variables.get("nonce")"#,
+        ))
+        .unwrap();
+    assert_eq!("undefinednull", result);
+    assert_eq!("{}", engine.snapshot().unwrap());
+}
+
+#[test]
+fn test_run_variables() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+    engine
+        .execute_script(&Script::internal_script(r#"client.run.set("ids", [1, 2])"#))
+        .unwrap();
+
+    engine.reset().unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"ids.length + client.run.get("ids")[1]"#,
+        ))
+        .unwrap();
+    assert_eq!("4", result);
+    assert_eq!("{}", engine.snapshot().unwrap());
+
+    engine.reset_run().unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"typeof ids + client.run.get("ids")"#,
+        ))
+        .unwrap();
+    assert_eq!("undefinednull", result);
+}
+
+#[test]
+fn test_reset_restores_shadowed_variables() {
+    let mut engine = create_script_engine(r#"{"dev": {"host": "env"}}"#, "dev", "{}");
+    engine
+        .execute_script(&Script::internal_script(
+            r#"client.run.set("host", "run"); request.variables.set("host", "request")"#,
+        ))
+        .unwrap();
+    assert_eq!(
+        "request",
+        engine
+            .execute_script(&Script::internal_script("host"))
+            .unwrap()
+    );
+
+    engine.reset().unwrap();
+    assert_eq!(
+        "run",
+        engine
+            .execute_script(&Script::internal_script("host"))
+            .unwrap()
+    );
+
+    engine.reset_run().unwrap();
+    assert_eq!(
+        "env",
+        engine
+            .execute_script(&Script::internal_script("host"))
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_functions_survive_reset() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+    engine
+        .execute_script(&Script::internal_script(
+            "var double = function (x) { return x * 2; };",
+        ))
+        .unwrap();
+
+    engine.reset().unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script("double(21)"))
+        .unwrap();
+    assert_eq!("42", result);
+}
+
+#[test]
+fn test_reset_run_clears_named_responses() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+    let response = Response {
+        version: Version::Http11,
+        headers: vec![],
+        body: None,
+        status_code: 201,
+        status: "".to_string(),
+        redirects: vec![],
+        proxy: None,
+    };
+    engine.declare_response("created", &response).unwrap();
+    engine.reset().unwrap();
+    assert_eq!(
+        "201",
+        engine
+            .execute_script(&Script::internal_script("created.response.status"))
+            .unwrap()
+    );
+
+    engine.reset_run().unwrap();
+    assert_eq!(
+        "undefined",
+        engine
+            .execute_script(&Script::internal_script("typeof created"))
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_handlers_can_be_run_twice() {
+    let mut engine = create_script_engine("{}", "dev", "{}");
+    let script = "var token = 'first'; { var count = 1; } function helper() { return 2; }";
+    engine
+        .execute_script(&Script::internal_script(script))
+        .unwrap();
+    let script = "var token = 'second'; { var count = 2; } function helper() { return 3; }";
+    engine
+        .execute_script(&Script::internal_script(script))
+        .unwrap();
+    assert_eq!(
+        "second 2 3",
+        engine
+            .execute_script(&Script::internal_script(
+                "token + ' ' + count + ' ' + helper()"
+            ))
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_handlers_can_redeclare_injected_variables() {
+    let mut engine = create_script_engine(r#"{"dev": {"token": "env"}}"#, "dev", "{}");
+    for _ in 0..2 {
+        engine.reset().unwrap();
+        engine
+            .execute_script(&Script::internal_script(
+                "var token = 'handler'; var response = 'replaced';",
+            ))
+            .unwrap();
+    }
+    assert_eq!(
+        "handler replaced",
+        engine
+            .execute_script(&Script::internal_script("token + ' ' + response"))
+            .unwrap()
+    );
+}
+
+#[cfg(feature = "quickjs")]
+#[test]
+fn test_quickjs_modern_syntax() {
+    let mut engine = create_engine(
+        Engine::QuickJs,
+        r#"{"dev": {"user": "alice"}}"#,
+        "dev",
+        "{}",
+        "{}",
+        &Limits::default(),
+    )
+    .unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"
+            class Greeter { greet(name) { return `hello ${name}`; } }
+            const { length } = [1, 2, 3];
+            new Greeter().greet(user) + ' ' + length
+            "#,
+        ))
+        .unwrap();
+
+    assert_eq!("hello alice 3", result);
+}
+
+#[cfg(feature = "rusty_v8")]
+#[test]
+fn test_v8_globals() {
+    let mut engine = create_engine(
+        Engine::V8,
+        r#"{"dev": {"user": "alice'); throw 'injected'; ('"}}"#,
+        "dev",
+        r#"{"token": "abc"}"#,
+        r#"{"row": {"tags": ["a", "b"]}}"#,
+        &Limits::default(),
+    )
+    .unwrap();
+
+    engine
+        .set_global("_cookies", &serde_json::json!({"session": "s-1"}))
+        .unwrap();
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "[user, token, row.tags[1], _cookies.session].join(' ')",
+        ))
+        .unwrap();
+
+    assert_eq!("alice'); throw 'injected'; (' abc b s-1", result);
+}
+
+#[test]
+fn test_default_engine_compiled() {
+    let result = create_engine(
+        Engine::default(),
+        "{}",
+        "dev",
+        "{}",
+        "{}",
+        &Limits::default(),
+    );
+
+    assert!(result.is_ok(), "{:?}", result.err());
+}
+
+#[cfg(not(feature = "rusty_v8"))]
+#[test]
+fn test_engine_not_compiled() {
+    let result = create_engine(Engine::V8, "{}", "dev", "{}", "{}", &Limits::default());
+
+    assert!(result.is_err(), "Should've been an error");
+}
+
+#[test]
+fn test_overrides() {
+    let mut engine = create_engine(
+        Engine::default(),
+        r#"{"dev": {"user": "env", "host": "localhost"}}"#,
+        "dev",
+        r#"{"user": "snapshot"}"#,
+        r#"{"user": "cli", "filter": {"a": 1}}"#,
+        &Limits::default(),
+    )
+    .unwrap();
+
+    assert_eq!(interpolate(&mut *engine, "user"), "cli");
+    assert_eq!(interpolate(&mut *engine, "filter"), r#"{"a":1}"#);
+    assert_eq!(interpolate(&mut *engine, "host"), "localhost");
+
+    engine
+        .execute_script(&Script::internal_script(
+            "client.global.set('user', 'handler'); request.variables.set('host', 'request');",
+        ))
+        .unwrap();
+    assert_eq!(interpolate(&mut *engine, "user"), "cli");
+    assert_eq!(
+        interpolate(&mut *engine, "client.global.get('user')"),
+        "cli"
+    );
+    assert_eq!(interpolate(&mut *engine, "host"), "request");
+
+    engine
+        .execute_script(&Script::internal_script(
+            "request.variables.set('user', 'request');",
+        ))
+        .unwrap();
+    assert_eq!(interpolate(&mut *engine, "user"), "request");
+    engine.reset().unwrap();
+    assert_eq!(interpolate(&mut *engine, "user"), "cli");
+
+    let snapshot: serde_json::Value = serde_json::from_str(&engine.snapshot().unwrap()).unwrap();
+    assert_eq!(snapshot, serde_json::json!({"user": "handler"}));
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_overrides() {
+    let mut engine = create_engine(
+        Engine::Rhai,
+        r#"{"dev": {"user": "env"}}"#,
+        "dev",
+        r#"{"user": "snapshot"}"#,
+        r#"{"user": "cli"}"#,
+        &Limits::default(),
+    )
+    .unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"
+            client.global.set("user", "handler");
+            `${user} ${client.global.get("user")}`
+            "#,
+        ))
+        .unwrap();
+    assert_eq!("cli cli", result);
+    assert_eq!(r#"{"user":"handler"}"#, engine.snapshot().unwrap());
+}
+
+fn declare_variables(engine: Engine, script: &str) {
+    let mut engine = create_engine(
+        engine,
+        r#"{"dev": {"name": "env"}}"#,
+        "dev",
+        "{}",
+        "{}",
+        &Limits::default(),
+    )
+    .unwrap();
+
+    let row = serde_json::json!({"name": "ada", "tags": ["a", "b"]});
+    engine.declare_variables(row.as_object().unwrap()).unwrap();
+    let result = engine
+        .execute_script(&Script::internal_script(script))
+        .unwrap();
+    assert_eq!("ada b", result);
+
+    engine.reset().unwrap();
+    let result = engine
+        .execute_script(&Script::internal_script("name"))
+        .unwrap();
+    assert_eq!("env", result);
+}
+
+#[test]
+fn test_declare_variables() {
+    declare_variables(
+        Engine::default(),
+        r#"name + " " + request.variables.get("tags")[1]"#,
+    );
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_declare_variables() {
+    declare_variables(
+        Engine::Rhai,
+        r#"`${name} ${request.variables.get("tags")[1]}`"#,
+    );
+}
+
+fn control(engine: Engine, goto: &str) {
+    let mut engine = create_engine(engine, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+    let response = Response {
+        version: Version::Http11,
+        headers: vec![],
+        body: None,
+        status_code: 200,
+        status: "OK".to_string(),
+        redirects: vec![],
+        proxy: None,
+    };
+
+    let control = engine.pre_request(&Script::internal_script(goto)).unwrap();
+    assert_eq!(control, Control::Goto("second".to_string()));
+    let control = engine
+        .pre_request(&Script::internal_script("client.run.set(\"a\", 1);"))
+        .unwrap();
+    assert_eq!(control, Control::Continue);
+    let control = engine
+        .pre_request(&Script::internal_script("client.skip();"))
+        .unwrap();
+    assert_eq!(control, Control::Skip);
+
+    let control = engine
+        .handle(&Script::internal_script("client.exit(3);"), &response)
+        .unwrap();
+    assert_eq!(control, Control::Exit(3));
+    let control = engine
+        .handle(&Script::internal_script("client.exit();"), &response)
+        .unwrap();
+    assert_eq!(control, Control::Exit(0));
+}
+
+#[test]
+fn test_control() {
+    control(Engine::default(), "client.goto('second');");
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_control() {
+    control(Engine::Rhai, "client.go_to(\"second\");");
+}
+
+fn cookies(engine: Engine) {
+    let mut engine = create_engine(engine, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+    engine
+        .set_cookies(
+            r#"[{"name": "session", "value": "abc", "path": "/"},
+                {"name": "tracker", "value": "1", "path": "/"},
+                {"name": "quoted", "value": "it's \"x\"\u2028", "path": "/"}]"#,
+        )
+        .unwrap();
+    let quoted = engine.execute_script(&Script::internal_script(r#"client.cookies.get("quoted")"#));
+    assert_eq!(quoted.unwrap(), "it's \"x\"\u{2028}");
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"
+            client.cookies.set("theme", "dark");
+            client.cookies.set("session", "def", "localhost");
+            client.cookies.remove("tracker");
+            client.cookies.remove("quoted");
+            client.cookies.get("session")
+            "#,
+        ))
+        .unwrap();
+    assert_eq!("def", result);
+
+    let cookies: serde_json::Value = serde_json::from_str(&engine.cookies().unwrap()).unwrap();
+    assert_eq!(
+        cookies,
+        serde_json::json!([
+            {"name": "session", "value": "def", "path": "/", "domain": "localhost"},
+            {"name": "theme", "value": "dark", "path": "/"}
+        ])
+    );
+}
+
+#[test]
+fn test_cookies() {
+    cookies(Engine::default());
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_cookies() {
+    cookies(Engine::Rhai);
+}
+
+#[test]
+fn test_control_from_str() {
+    assert_eq!(Control::Continue, "".parse().unwrap());
+    assert_eq!(Control::Exit(-1), "exit -1".parse().unwrap());
+    assert_eq!(
+        Control::Goto("with spaces".to_string()),
+        "goto with spaces".parse().unwrap()
+    );
+    assert!("exit now".parse::<Control>().is_err());
+    assert!("stop".parse::<Control>().is_err());
+}
+
+#[test]
+fn test_engine_from_str() {
+    assert_eq!(Engine::QuickJs, "quickjs".parse().unwrap());
+    assert!("spidermonkey".parse::<Engine>().is_err());
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_variables() {
+    let mut engine = create_engine(
+        Engine::Rhai,
+        r#"{"dev": {"host": "localhost", "token": "env"}}"#,
+        "dev",
+        r#"{"token": "snapshot"}"#,
+        "{}",
+        &Limits::default(),
+    )
+    .unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"
+            client.global.set("id", 42);
+            client.run.set("run_id", client.global.get("id") + 1);
+            let local = "kept";
+            `${host} ${token} ${id} ${run_id}`
+            "#,
+        ))
+        .unwrap();
+    assert_eq!("localhost snapshot 42 43", result);
+
+    engine.reset_run().unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            "`${id} ${local} ${type_of(run_id)}`",
+        ))
+        .unwrap_err();
+    assert!(
+        result.to_string().contains("run_id"),
+        "Should've been an unknown variable error, but instead got:\n {:#?}",
+        result
+    );
+    assert_eq!(
+        r#"{"token":"snapshot","id":42}"#,
+        engine.snapshot().unwrap()
+    );
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_response() {
+    let mut engine =
+        create_engine(Engine::Rhai, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+
+    let response = Response {
+        version: Version::Http11,
+        headers: vec![("X-Auth-Token".to_string(), "SomeTokenValue".to_string())],
+        body: Some(r#"{"items": [{"id": 1}, {"id": 2}]}"#.to_string()),
+        status_code: 200,
+        status: "OK".to_string(),
+        redirects: vec![],
+        proxy: None,
+    };
+    let script = r#"
+        request.variables.set("second", response.body.items[1].id);
+        client.global.set("header", response.headers["X-Auth-Token"]);
+    "#;
+    engine
+        .handle(&Script::internal_script(script), &response)
+        .unwrap();
+    engine.declare_response("listed", &response).unwrap();
+
+    let value = Value {
+        state: Unprocessed::WithInline {
+            value: "{{second}} {{header}} {{listed.response.body.$.items[0].id}}".to_string(),
+            inline_scripts: vec![
+                InlineScript {
+                    script: "second".to_string(),
+                    placeholder: "{{second}}".to_string(),
+                    selection: Selection::none(),
+                },
+                InlineScript {
+                    script: "header".to_string(),
+                    placeholder: "{{header}}".to_string(),
+                    selection: Selection::none(),
+                },
+                InlineScript {
+                    script: "listed.response.body.$.items[0].id".to_string(),
+                    placeholder: "{{listed.response.body.$.items[0].id}}".to_string(),
+                    selection: Selection::none(),
+                },
+            ],
+            selection: Selection::none(),
+        },
+    };
+    assert_eq!(
+        "2 SomeTokenValue 1",
+        engine.process(value).unwrap().state.value
+    );
+
+    engine.reset().unwrap();
+    assert!(engine
+        .execute_script(&Script::internal_script("second"))
+        .is_err());
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_helpers() {
+    let mut engine =
+        create_engine(Engine::Rhai, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+
+    let result = engine
+        .execute_script(&Script::internal_script(
+            r#"
+            let signature = crypto.hmac.sha256().withTextSecret("secret").updateWithText("body").digest().toHex();
+            `${Base64.encode("hi")} ${encodeURIComponent("a b")} ${signature}`
+            "#,
+        ))
+        .unwrap();
+
+    assert_eq!(
+        "aGk= a%20b dc46983557fea127b43af721467eb9b3fde2338fe3e14f51952aa8478c13d355",
+        result
+    );
+}
+
+fn limited_engine(limits: Limits) -> Box<dyn ScriptEngine> {
+    create_engine(Engine::default(), "{}", "dev", "{}", "{}", &limits).unwrap()
+}
+
+fn selected_script(src: &str) -> Script<'_> {
+    Script {
+        selection: Selection {
+            filename: "handlers.http".into(),
+            start: Position { line: 3, col: 5 },
+            end: Position { line: 5, col: 1 },
+        },
+        src,
+    }
+}
+
+#[test]
+fn test_timeout() {
+    let mut engine = limited_engine(Limits {
+        timeout: Some(Duration::from_millis(100)),
+        ..Limits::default()
+    });
+
+    let result = engine.execute_script(&selected_script("while (true) {}"));
+
+    assert_eq!(
+        "The script at handlers.http:3:5 exceeded the time limit of 100ms",
+        result.unwrap_err().to_string()
+    );
+    assert_eq!(
+        "2",
+        engine
+            .execute_script(&Script::internal_script("1 + 1"))
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_max_operations() {
+    let mut engine = limited_engine(Limits {
+        max_operations: Some(1000),
+        ..Limits::default()
+    });
+
+    let result = engine.execute_script(&selected_script(
+        "var i = 0; while (i < 100) { i = i + 1; } i",
+    ));
+    assert_eq!("100", result.unwrap());
+
+    let result = engine.execute_script(&selected_script(
+        "function spin() { while (true) {} } spin();",
+    ));
+    assert_eq!(
+        "The script at handlers.http:3:5 exceeded the limit of 1000 operations",
+        result.unwrap_err().to_string()
+    );
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_limits() {
+    let limits = Limits {
+        max_operations: Some(1000),
+        ..Limits::default()
+    };
+    let mut engine = create_engine(Engine::Rhai, "{}", "dev", "{}", "{}", &limits).unwrap();
+
+    let result = engine.execute_script(&selected_script("loop {}"));
+
+    assert_eq!(
+        "The script at handlers.http:3:5 exceeded the limit of 1000 operations",
+        result.unwrap_err().to_string()
+    );
+}
+
+#[cfg(feature = "rhai")]
+#[test]
+fn test_rhai_error_position() {
+    let mut engine =
+        create_engine(Engine::Rhai, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+
+    let result = engine.execute_script(&selected_script("let a = 1;\n  undefined_fn();"));
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.ends_with(" at handlers.http:4:3"), "{}", error);
+}
+
+#[cfg(feature = "quickjs")]
+#[test]
+fn test_quickjs_error_position() {
+    let mut engine =
+        create_engine(Engine::QuickJs, "{}", "dev", "{}", "{}", &Limits::default()).unwrap();
+
+    let result = engine.execute_script(&selected_script("var a = 1;\nundefinedFunction();"));
+
+    let error = result.unwrap_err().to_string();
+    assert!(error.ends_with(" at handlers.http:4:1"), "{}", error);
+}
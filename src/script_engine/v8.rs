@@ -1,26 +1,94 @@
-use crate::script_engine::{handle, Script, ScriptEngine};
+use crate::script_engine::limits::{self, Exceeded, Limits};
+use crate::script_engine::natives::{self, Native};
+use crate::script_engine::{handle, initial_globals, Control, Script, ScriptEngine};
 use crate::Result;
 use rusty_v8::{
     inspector::{
         StringView, V8Inspector, V8InspectorClientBase, V8InspectorClientImpl, V8StackTrace,
     },
+    json,
     scope::Entered,
-    Context, ContextScope, Exception, Global, HandleScope, Isolate, OwnedIsolate,
-    Script as V8Script, String as V8String, TryCatch, V8,
+    Context, ContextScope, Exception, FunctionCallbackArguments, FunctionCallbackScope,
+    FunctionTemplate, Global, HandleScope, Isolate, IsolateHandle, Local, OwnedIsolate,
+    ReturnValue, Script as V8Script, String as V8String, ToLocal, TryCatch, V8,
 };
 use std::convert::From;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::Once;
+use std::thread;
+use std::time::Duration;
 
 static V8_INIT: Once = Once::new();
 pub struct V8ScriptEngine {
     isolate: OwnedIsolate,
     global: Global<Context>,
-    env_file: String,
-    env: String,
+    watchdog: Option<Watchdog>,
+}
+
+/// Terminates the isolate once a script runs past its timeout. A single thread lives as long as
+/// the engine, armed when a script starts and disarmed when it ends.
+struct Watchdog {
+    timeout: Duration,
+    handle: IsolateHandle,
+    commands: Sender<()>,
+    fired: Receiver<bool>,
+}
+
+impl Watchdog {
+    fn new(handle: IsolateHandle, timeout: Duration) -> Watchdog {
+        let (commands, received) = channel::<()>();
+        let (reply, fired) = channel::<bool>();
+        let isolate = handle.clone();
+        // The thread ends once the engine, and so the sender, is dropped
+        thread::spawn(move || {
+            while received.recv().is_ok() {
+                let fired = match received.recv_timeout(timeout) {
+                    Ok(()) => false,
+                    Err(RecvTimeoutError::Timeout) => {
+                        isolate.terminate_execution();
+                        if received.recv().is_err() {
+                            break;
+                        }
+                        true
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if reply.send(fired).is_err() {
+                    break;
+                }
+            }
+        });
+        Watchdog {
+            timeout,
+            handle,
+            commands,
+            fired,
+        }
+    }
+
+    fn arm(&self) {
+        let _ = self.commands.send(());
+    }
+
+    /// Returns whether the script was terminated for running past the timeout, the isolate being
+    /// able to run scripts again either way
+    fn disarm(&self) -> bool {
+        let fired = self.commands.send(()).is_ok() && self.fired.recv().unwrap_or(false);
+        if fired {
+            self.handle.cancel_terminate_execution();
+        }
+        fired
+    }
 }
 
 impl V8ScriptEngine {
-    pub fn new(env_script: &str, env: &str, snapshot_script: &str) -> Result<V8ScriptEngine> {
+    pub fn new(
+        env_script: &str,
+        env: &str,
+        snapshot_script: &str,
+        overrides_script: &str,
+        limits: &Limits,
+    ) -> Result<V8ScriptEngine> {
         V8_INIT.call_once(|| {
             let platform = rusty_v8::new_default_platform().unwrap();
             V8::initialize_platform(platform);
@@ -31,38 +99,52 @@ impl V8ScriptEngine {
         // otherwise the v8 script engine crash
         let mut engine = {
             let mut isolate = Isolate::new(Default::default());
+            let watchdog = limits
+                .timeout
+                .map(|timeout| Watchdog::new(isolate.thread_safe_handle(), timeout));
+
             let mut global = Global::<Context>::new();
             let mut handle_scope = HandleScope::new(&mut isolate);
             let scope = handle_scope.enter();
             let context = Context::new(scope);
             global.set(scope, context);
 
+            let mut context_scope = ContextScope::new(scope, context);
+            let scope = context_scope.enter();
+            let target = context.global(scope);
+            macro_rules! register {
+                ($target:ident, $name:literal, $kind:ident($native:ident)) => {{
+                    fn native(
+                        scope: FunctionCallbackScope,
+                        args: FunctionCallbackArguments,
+                        rv: ReturnValue,
+                    ) {
+                        call_native(Native::$kind(natives::$native), scope, args, rv)
+                    }
+                    let function = FunctionTemplate::new(scope, native)
+                        .get_function(scope, context)
+                        .unwrap();
+                    let name = V8String::new(scope, $name).unwrap();
+                    $target.set(context, name.into(), function.into());
+                }};
+            }
+            for_each_native!(register, target);
+
+            for (name, value) in
+                initial_globals(env_script, env, snapshot_script, overrides_script)?
+            {
+                let name = V8String::new(scope, &name).unwrap();
+                let value = from_json(scope, context, &value);
+                target.set(context, name.into(), value);
+            }
+
             V8ScriptEngine {
                 isolate,
                 global,
-                env_file: env_script.to_string(),
-                env: env.to_string(),
+                watchdog,
             }
         };
 
-        let environment: serde_json::Value = serde_json::from_str(env_script)?;
-        if let Some(environment) = environment.get(env) {
-            declare(&mut engine, environment);
-            let script = format!(
-                r#"
-            var _env_file = {};
-            var _env = _env_file['{}'];
-            "#,
-                &env_script, &env
-            );
-            engine.execute_script(&Script::internal_script(script.as_str()))?;
-        }
-
-        let snapshot: serde_json::Value = serde_json::from_str(snapshot_script).unwrap();
-        declare(&mut engine, &snapshot);
-        let snapshot = format!("var _snapshot = {};", snapshot);
-        engine.execute_script(&Script::internal_script(snapshot.as_str()))?;
-
         let script = include_str!("init.js");
         engine.execute_script(&Script::internal_script(script))?;
 
@@ -72,14 +154,128 @@ impl V8ScriptEngine {
 fn catch(
     tc: &mut TryCatch,
     scope: &mut Entered<ContextScope, Entered<HandleScope, OwnedIsolate>>,
+    script: &Script,
 ) -> anyhow::Error {
-    let exception = tc.exception(scope).unwrap();
+    // A terminated script has no exception
+    let exception = match tc.exception(scope) {
+        Some(exception) => exception,
+        None => return anyhow!("Script execution was terminated"),
+    };
     let msg = Exception::create_message(scope, exception);
-    anyhow!("{}", msg.get(scope).to_rust_string_lossy(scope))
+    let message = msg.get(scope).to_rust_string_lossy(scope);
+    let context = scope.get_current_context().unwrap();
+    match msg.get_line_number(context) {
+        Some(line) if !script.selection.filename.as_os_str().is_empty() => {
+            let selection = script.locate(line, msg.get_start_column() + 1);
+            anyhow!("{} at {}", message, selection)
+        }
+        _ => anyhow!("{}", message),
+    }
+}
+
+fn to_json<'sc>(
+    scope: &mut impl ToLocal<'sc>,
+    context: Local<'sc, Context>,
+    value: Local<'sc, rusty_v8::Value>,
+) -> serde_json::Value {
+    let value = json::stringify(scope, context, value)
+        .map(|value| value.to_rust_string_lossy(scope))
+        .unwrap_or_default();
+    serde_json::from_str(&value).unwrap_or(serde_json::Value::Null)
+}
+
+fn from_json<'sc>(
+    scope: &mut impl ToLocal<'sc>,
+    context: Local<'sc, Context>,
+    value: &serde_json::Value,
+) -> Local<'sc, rusty_v8::Value> {
+    let value = V8String::new(scope, &value.to_string()).unwrap();
+    json::parse(scope, context, value).unwrap()
+}
+
+fn call_native(
+    native: Native,
+    scope: FunctionCallbackScope,
+    args: FunctionCallbackArguments,
+    mut rv: ReturnValue,
+) {
+    // The arguments and the callback scope don't share a lifetime, a scope of its own gives their
+    // handles one
+    let mut handle_scope = HandleScope::new(scope);
+    let scope = handle_scope.enter();
+    let context = scope.get_current_context().unwrap();
+    let arguments = (0..args.length())
+        .map(|index| to_json(scope, context, args.get(index)))
+        .collect::<Vec<serde_json::Value>>();
+    let this = args.this();
+    let result = match native {
+        Native::Function(function) => function(&arguments).map(Some),
+        Native::Method(method) => match to_json(scope, context, this.into()) {
+            serde_json::Value::Object(mut fields) => {
+                let result = method(&mut fields, &arguments);
+                for (key, value) in fields {
+                    let key = V8String::new(scope, &key).unwrap();
+                    let value = from_json(scope, context, &value);
+                    this.set(context, key.into(), value);
+                }
+                result
+            }
+            _ => Err(anyhow!("Method called without an object receiver")),
+        },
+    };
+    match result {
+        Ok(Some(result)) => rv.set(from_json(scope, context, &result)),
+        Ok(None) => rv.set(this.into()),
+        Err(error) => {
+            let message = V8String::new(scope, &error.to_string()).unwrap();
+            let exception = Exception::error(scope, message);
+            scope.isolate().throw_exception(exception);
+        }
+    }
 }
 
 impl ScriptEngine for V8ScriptEngine {
     fn execute_script(&mut self, script: &Script) -> Result<String> {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.arm();
+        }
+        let result = self.run(script);
+        if let Some(watchdog) = &self.watchdog {
+            if watchdog.disarm() {
+                return Err(limits::exceeded(
+                    script,
+                    Exceeded::Timeout(watchdog.timeout),
+                ));
+            }
+        }
+        result
+    }
+
+    fn set_global(&mut self, name: &str, value: &serde_json::Value) -> Result<()> {
+        let mut scope = HandleScope::new(&mut self.isolate);
+        let scope = scope.enter();
+        let context = self.global.get(scope).unwrap();
+        let mut scope = ContextScope::new(scope, context);
+        let scope = scope.enter();
+        let name = V8String::new(scope, name).unwrap();
+        let value = from_json(scope, context, value);
+        context.global(scope).set(context, name.into(), value);
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Result<String> {
+        let script = "JSON.stringify(_snapshot)";
+        let out = self.execute_script(&Script::internal_script(script))?;
+        Ok(out)
+    }
+
+    fn handle(&mut self, script: &Script, response: &crate::Response) -> Result<Control> {
+        handle(self, script, response)
+    }
+}
+
+impl V8ScriptEngine {
+    fn run(&mut self, script: &Script) -> Result<String> {
         let isolate = &mut self.isolate;
         let mut logger = ConsoleLogger::new();
         let mut inspector = V8Inspector::create(isolate, &mut logger);
@@ -99,49 +295,15 @@ impl ScriptEngine for V8ScriptEngine {
         let try_catch = try_catch.enter();
         let source = V8String::new(scope, script.src).unwrap();
         let mut compiled = V8Script::compile(scope, context, source, None)
-            .ok_or_else(|| catch(try_catch, scope))?;
+            .ok_or_else(|| catch(try_catch, scope, script))?;
         let result = compiled
             .run(scope, context)
-            .ok_or_else(|| catch(try_catch, scope))?;
+            .ok_or_else(|| catch(try_catch, scope, script))?;
 
         let result = result.to_string(scope).unwrap();
 
         Ok(result.to_rust_string_lossy(scope))
     }
-
-    fn empty(&self) -> String {
-        "{}".to_string()
-    }
-
-    fn reset(&mut self) -> Result<()> {
-        let snapshot = self.snapshot()?;
-        *self = V8ScriptEngine::new(self.env_file.as_str(), self.env.as_str(), snapshot.as_str())?;
-        Ok(())
-    }
-
-    fn snapshot(&mut self) -> Result<String> {
-        let script = "JSON.stringify(_snapshot)";
-        let out = self.execute_script(&Script::internal_script(script))?;
-        Ok(out)
-    }
-
-    fn handle(&mut self, script: &Script, response: &crate::Response) -> Result<()> {
-        handle(self, script, response)
-    }
-}
-
-fn declare(engine: &mut V8ScriptEngine, variables_object: &serde_json::Value) {
-    let map = variables_object.as_object().unwrap();
-    for (key, value) in map {
-        let script = format!(
-            "this['{}'] = {};",
-            key,
-            serde_json::to_string(&value).unwrap()
-        );
-        engine
-            .execute_script(&Script::internal_script(script.as_str()))
-            .unwrap();
-    }
 }
 
 struct ConsoleLogger {
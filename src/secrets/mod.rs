@@ -0,0 +1,148 @@
+use crate::Result;
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(test)]
+mod tests;
+
+/// The key of the environment file section declaring secrets
+pub const SECTION: &str = "secrets";
+
+/// What replaces secrets in the output
+pub const MASK: &str = "********";
+
+/// Where the value of a secret comes from
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// A command printing the secret, run through the shell
+    Command(String),
+    /// A file holding the secret, relative to the environment file
+    File(PathBuf),
+}
+
+/// The secrets declared by the environment files, resolved on first use and kept for the run
+#[derive(Debug, Default)]
+pub struct Secrets {
+    providers: HashMap<String, Provider>,
+    directory: PathBuf,
+    resolved: HashMap<String, String>,
+}
+
+impl Secrets {
+    /// Reads the `secrets` section of an environment file
+    pub fn from_section(section: Option<&Value>, directory: &Path) -> Result<Secrets> {
+        let providers = match section {
+            Some(section) => serde_json::from_value(section.clone()).with_context(|| {
+                "Invalid secrets section, expected {\"name\": {\"command\": \"...\"}} or {\"name\": {\"file\": \"...\"}}"
+            })?,
+            None => HashMap::new(),
+        };
+        Ok(Secrets {
+            providers,
+            directory: directory.to_path_buf(),
+            resolved: HashMap::new(),
+        })
+    }
+
+    /// The value of a secret, running its provider the first time it is asked for
+    pub fn resolve(&mut self, name: &str) -> Result<&str> {
+        if !self.resolved.contains_key(name) {
+            let provider = self
+                .providers
+                .get(name)
+                .ok_or_else(|| anyhow!("Unknown secret: {}", name))?;
+            let value = provide(provider, &self.directory)
+                .with_context(|| format!("Failed resolving secret: {}", name))?;
+            self.resolved.insert(name.to_string(), value);
+        }
+        Ok(self.resolved[name].as_str())
+    }
+
+    /// The values resolved so far
+    pub fn values(&self) -> impl Iterator<Item = &str> {
+        self.resolved
+            .values()
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Drops the variables of a snapshot holding a resolved secret, so none is written to disk
+    pub fn scrub(&self, snapshot: &mut Value) {
+        if let Value::Object(variables) = snapshot {
+            variables.retain(|_, value| !self.holds_secret(value));
+        }
+    }
+
+    fn holds_secret(&self, value: &Value) -> bool {
+        match value {
+            Value::String(text) => self.values().any(|secret| text.contains(secret)),
+            Value::Array(values) => values.iter().any(|value| self.holds_secret(value)),
+            Value::Object(fields) => fields.iter().any(|(key, value)| {
+                self.holds_secret(&Value::String(key.clone())) || self.holds_secret(value)
+            }),
+            _ => false,
+        }
+    }
+}
+
+fn provide(provider: &Provider, directory: &Path) -> Result<String> {
+    let value = match provider {
+        Provider::Command(command) => {
+            let output = shell(command)
+                .current_dir(directory)
+                .output()
+                .with_context(|| format!("Failed running: {}", command))?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "`{}` failed with {}: {}",
+                    command,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            String::from_utf8(output.stdout)
+                .with_context(|| format!("`{}` printed invalid UTF-8", command))?
+        }
+        Provider::File(path) => {
+            let path = directory.join(path);
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed opening secret file: {:?}", path))?
+        }
+    };
+    Ok(value.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
+
+/// The name of the secret an inline script refers to, `$secret api/token` refers to `api/token`
+pub fn secret_name(script: &str) -> Option<&str> {
+    let rest = script.strip_prefix("$secret")?;
+    let name = rest.trim();
+    if rest.starts_with(char::is_whitespace) && !name.is_empty() {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+/// Replaces every resolved secret in a text with the mask
+pub fn mask<'a>(text: &str, secrets: impl Iterator<Item = &'a str>) -> String {
+    secrets.fold(text.to_string(), |text, secret| text.replace(secret, MASK))
+}
@@ -0,0 +1,95 @@
+use super::*;
+use serde_json::json;
+
+fn secrets(section: Value, directory: &Path) -> Secrets {
+    Secrets::from_section(Some(&section), directory).unwrap()
+}
+
+#[test]
+fn test_secret_name() {
+    assert_eq!(secret_name("$secret api/token"), Some("api/token"));
+    assert_eq!(secret_name("$secret   token "), Some("token"));
+    assert_eq!(secret_name("$secret"), None);
+    assert_eq!(secret_name("$secrets.token"), None);
+    assert_eq!(secret_name("secret token"), None);
+}
+
+#[test]
+fn test_resolve_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut secrets = secrets(json!({"token": {"command": "echo s3cr3t"}}), dir.path());
+
+    assert_eq!(secrets.resolve("token").unwrap(), "s3cr3t");
+    assert_eq!(secrets.values().collect::<Vec<_>>(), vec!["s3cr3t"]);
+}
+
+#[test]
+fn test_resolve_file() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("key.txt"), "k3y\n").unwrap();
+    let mut secrets = secrets(json!({"key": {"file": "key.txt"}}), dir.path());
+
+    assert_eq!(secrets.resolve("key").unwrap(), "k3y");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_resolve_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut secrets = secrets(
+        json!({"counter": {"command": "echo run >> runs.txt && wc -l < runs.txt"}}),
+        dir.path(),
+    );
+
+    assert_eq!(secrets.resolve("counter").unwrap().trim(), "1");
+    assert_eq!(secrets.resolve("counter").unwrap().trim(), "1");
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("runs.txt")).unwrap(),
+        "run\n"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_resolve_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut secrets = secrets(
+        json!({"failing": {"command": "echo denied >&2; exit 3"}}),
+        dir.path(),
+    );
+
+    let error = secrets.resolve("failing").unwrap_err();
+    assert_eq!(error.to_string(), "Failed resolving secret: failing");
+    assert!(format!("{:#}", error).ends_with("denied"));
+
+    let error = secrets.resolve("missing").unwrap_err();
+    assert_eq!(error.to_string(), "Unknown secret: missing");
+
+    let error = Secrets::from_section(Some(&json!({"token": "plain"})), dir.path()).unwrap_err();
+    assert!(error.to_string().starts_with("Invalid secrets section"));
+}
+
+#[test]
+fn test_scrub() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut secrets = secrets(json!({"token": {"command": "echo s3cr3t"}}), dir.path());
+    secrets.resolve("token").unwrap();
+    let mut snapshot = json!({
+        "id": 30,
+        "header": "Bearer s3cr3t",
+        "nested": {"list": ["s3cr3t"]},
+        "kept": {"name": "public"}
+    });
+
+    secrets.scrub(&mut snapshot);
+
+    assert_eq!(snapshot, json!({"id": 30, "kept": {"name": "public"}}));
+}
+
+#[test]
+fn test_mask() {
+    assert_eq!(
+        mask("user s3cr3t k3y", vec!["s3cr3t", "k3y"].into_iter()),
+        "user ******** ********"
+    );
+}
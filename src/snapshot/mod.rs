@@ -0,0 +1,355 @@
+use crate::Result;
+use anyhow::Context;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(test)]
+mod tests;
+
+/// The current layout of the snapshot file, flat snapshots written before it have no version
+pub const VERSION: u64 = 2;
+
+/// How global variables are shared between the scripts of an environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotScope {
+    /// Every script of an environment shares its global variables
+    #[default]
+    Environment,
+    /// Each script file has global variables of its own, within each environment
+    File,
+}
+
+impl SnapshotScope {
+    pub const NAMES: [&'static str; 2] = ["environment", "file"];
+}
+
+impl std::str::FromStr for SnapshotScope {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "environment" => Ok(SnapshotScope::Environment),
+            "file" => Ok(SnapshotScope::File),
+            name => Err(anyhow!("Unknown snapshot scope: {}", name)),
+        }
+    }
+}
+
+/// When global variables are written to the snapshot file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Persist {
+    /// After every request that completes, so a failing request keeps what the ones before it set
+    #[default]
+    Request,
+    /// Once all the requests of a run complete, a failing request discards the whole run
+    Success,
+}
+
+impl Persist {
+    pub const NAMES: [&'static str; 2] = ["request", "success"];
+}
+
+impl std::str::FromStr for Persist {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "request" => Ok(Persist::Request),
+            "success" => Ok(Persist::Success),
+            name => Err(anyhow!("Unknown persist mode: {}", name)),
+        }
+    }
+}
+
+/// Where global variables are kept in the snapshot: an environment, and a script file of it when
+/// scoped by file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+    pub env: String,
+    pub file: Option<String>,
+}
+
+impl Namespace {
+    pub fn new(scope: SnapshotScope, env: &str, snapshot_file: &Path, script_file: &Path) -> Self {
+        Namespace {
+            env: env.to_string(),
+            file: match scope {
+                SnapshotScope::Environment => None,
+                SnapshotScope::File => Some(file_key(snapshot_file, script_file)),
+            },
+        }
+    }
+}
+
+/// The key of a script file, its path relative to the directory of the snapshot file with `/`
+/// separators, or its absolute path when it lives elsewhere
+pub fn file_key(snapshot_file: &Path, script_file: &Path) -> String {
+    let canonical =
+        |path: &Path| std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let script_file = canonical(script_file);
+    let directory = match snapshot_file.parent() {
+        Some(parent) if parent != Path::new("") => canonical(parent),
+        _ => canonical(Path::new(".")),
+    };
+    match script_file.strip_prefix(&directory) {
+        Ok(relative) => relative
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/"),
+        Err(_) => script_file.to_string_lossy().into_owned(),
+    }
+}
+
+/// The global variables of every environment, as kept in the snapshot file
+///
+/// ```text
+/// {
+///     "$version": 2,
+///     "environments": {
+///         "dev": {
+///             "variables": {"token": "..."},
+///             "files": {"api/users.http": {"id": 7}}
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    #[serde(rename = "$version")]
+    version: u64,
+    #[serde(default)]
+    environments: BTreeMap<String, Environment>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Environment {
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    variables: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    files: BTreeMap<String, Map<String, Value>>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            version: VERSION,
+            environments: BTreeMap::new(),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Reads a snapshot file, a missing file is an empty snapshot
+    pub fn load(snapshot_file: &Path, env: &str) -> Result<Snapshot> {
+        match read_to_string(snapshot_file) {
+            Ok(script) => Snapshot::parse(&script, env)
+                .with_context(|| format!("Failed parsing snapshot file: {:?}", snapshot_file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Snapshot::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed opening snapshot file: {:?}", snapshot_file))
+            }
+        }
+    }
+
+    /// Parses a snapshot. The variables of a flat snapshot, written before snapshots were
+    /// namespaced, are moved to the environment `env`.
+    pub fn parse(script: &str, env: &str) -> Result<Snapshot> {
+        let value: Value = serde_json::from_str(script)?;
+        match value.get("$version").and_then(Value::as_u64) {
+            Some(VERSION) => Ok(serde_json::from_value(value)?),
+            Some(version) => Err(anyhow!("Unsupported snapshot version: {}", version)),
+            None => {
+                let variables = match value {
+                    Value::Object(variables) => variables,
+                    _ => return Err(anyhow!("Expected the snapshot to be an object")),
+                };
+                let mut snapshot = Snapshot::default();
+                if !variables.is_empty() {
+                    snapshot.environments.insert(
+                        env.to_string(),
+                        Environment {
+                            variables,
+                            files: BTreeMap::new(),
+                        },
+                    );
+                }
+                Ok(snapshot)
+            }
+        }
+    }
+
+    /// The global variables of a namespace, as the JSON object the engines expect
+    pub fn variables(&self, namespace: &Namespace) -> String {
+        let environment = self.environments.get(&namespace.env);
+        let variables = match &namespace.file {
+            None => environment.map(|environment| &environment.variables),
+            Some(file) => environment.and_then(|environment| environment.files.get(file)),
+        };
+        variables
+            .map(|variables| Value::Object(variables.clone()).to_string())
+            .unwrap_or_else(|| "{}".to_string())
+    }
+
+    /// Applies the changes between two engine snapshots of a namespace, leaving the variables
+    /// neither of them changed as they are
+    pub fn apply(&mut self, namespace: &Namespace, before: &str, after: &str) -> Result<()> {
+        let before = parse_variables(before)?;
+        let after = parse_variables(after)?;
+        self.update(namespace, |stored| {
+            for key in before.keys().filter(|key| !after.contains_key(*key)) {
+                stored.remove(key);
+            }
+            for (key, value) in after {
+                if before.get(&key) != Some(&value) {
+                    stored.insert(key, value);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn update(&mut self, namespace: &Namespace, update: impl FnOnce(&mut Map<String, Value>)) {
+        let environment = self.environments.entry(namespace.env.clone()).or_default();
+        match &namespace.file {
+            None => update(&mut environment.variables),
+            Some(file) => {
+                let variables = environment.files.entry(file.clone()).or_default();
+                update(variables);
+                if variables.is_empty() {
+                    environment.files.remove(file);
+                }
+            }
+        }
+        if environment.variables.is_empty() && environment.files.is_empty() {
+            self.environments.remove(&namespace.env);
+        }
+    }
+
+    /// The snapshot as written to the snapshot file
+    pub fn to_script(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+fn parse_variables(variables: &str) -> Result<Map<String, Value>> {
+    match serde_json::from_str(variables)? {
+        Value::Object(variables) => Ok(variables),
+        _ => Err(anyhow!("Expected the snapshot to be an object")),
+    }
+}
+
+/// The snapshot file, along with what it held when last read or written
+#[derive(Debug)]
+pub struct SnapshotFile {
+    path: PathBuf,
+    env: String,
+    snapshot: Snapshot,
+    /// Whether the file was replaced since its directory was last flushed to disk
+    unsynced: bool,
+}
+
+impl SnapshotFile {
+    pub fn load(path: &Path, env: &str) -> Result<SnapshotFile> {
+        Ok(SnapshotFile {
+            path: path.to_path_buf(),
+            env: env.to_string(),
+            snapshot: Snapshot::load(path, env)?,
+            unsynced: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn variables(&self, namespace: &Namespace) -> String {
+        self.snapshot.variables(namespace)
+    }
+
+    /// Writes the changes made to a namespace since `before`, if there are any. The file is locked
+    /// and read again, so changes written meanwhile by another invocation are kept, then replaced
+    /// at once. The directory entry of the replaced file is only flushed to disk by `sync`, once the
+    /// run is over.
+    pub fn persist(&mut self, namespace: &Namespace, before: &str, after: &str) -> Result<()> {
+        if parse_variables(before)? == parse_variables(after)? {
+            return Ok(());
+        }
+        let _lock = self.lock()?;
+        let mut snapshot = Snapshot::load(&self.path, &self.env)?;
+        snapshot.apply(namespace, before, after)?;
+        self.write(&snapshot.to_script()?)
+            .with_context(|| format!("Failed writing snapshot file: {:?}", self.path))?;
+        self.snapshot = snapshot;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    /// Flushes to disk the directory holding the snapshot, so the files `persist` renamed over it
+    /// since the last call stay in place
+    pub fn sync(&mut self) -> Result<()> {
+        if !self.unsynced {
+            return Ok(());
+        }
+        // Directories can't be opened as files on Windows, where a rename needs no flush
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("Failed syncing snapshot file: {:?}", self.path))?;
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Takes the lock file next to the snapshot, waiting for other invocations to release it. The
+    /// snapshot itself can't be locked as it is replaced on every write.
+    fn lock(&self) -> Result<File> {
+        let path = self.sibling("lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed opening snapshot lock file: {:?}", path))?;
+        lock.lock_exclusive()
+            .with_context(|| format!("Failed locking snapshot lock file: {:?}", path))?;
+        Ok(lock)
+    }
+
+    /// Writes a temporary file next to the snapshot, then renames it over the snapshot so that
+    /// readers only ever see a complete file
+    fn write(&self, script: &str) -> Result<()> {
+        let path = self.sibling(&format!("{}.tmp", std::process::id()));
+        let written = File::create(&path).and_then(|mut file| {
+            file.write_all(script.as_bytes())?;
+            file.sync_all()
+        });
+        match written.and_then(|_| std::fs::rename(&path, &self.path)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let _ = std::fs::remove_file(&path);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(extension);
+        self.path.with_file_name(name)
+    }
+}
@@ -0,0 +1,263 @@
+use super::*;
+use serde_json::json;
+
+fn namespace(env: &str, file: Option<&str>) -> Namespace {
+    Namespace {
+        env: env.to_string(),
+        file: file.map(str::to_string),
+    }
+}
+
+fn parsed(variables: &str) -> Value {
+    serde_json::from_str(variables).unwrap()
+}
+
+#[test]
+fn test_migrate_flat_snapshot() {
+    let snapshot = Snapshot::parse(r#"{"token": "abc"}"#, "dev").unwrap();
+
+    assert_eq!(
+        snapshot.variables(&namespace("dev", None)),
+        r#"{"token":"abc"}"#
+    );
+    assert_eq!(snapshot.variables(&namespace("prod", None)), "{}");
+    assert_eq!(
+        snapshot.to_script().unwrap(),
+        r#"{"$version":2,"environments":{"dev":{"variables":{"token":"abc"}}}}"#
+    );
+
+    let empty = Snapshot::parse("{}", "dev").unwrap();
+    assert_eq!(empty, Snapshot::default());
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = Snapshot::parse(r#"{"$version": 3}"#, "dev").unwrap_err();
+    assert_eq!(error.to_string(), "Unsupported snapshot version: 3");
+
+    let error = Snapshot::parse("[]", "dev").unwrap_err();
+    assert_eq!(error.to_string(), "Expected the snapshot to be an object");
+}
+
+#[test]
+fn test_namespaces() {
+    let mut snapshot = Snapshot::default();
+    snapshot
+        .apply(&namespace("dev", None), "{}", r#"{"token": "dev"}"#)
+        .unwrap();
+    snapshot
+        .apply(&namespace("prod", None), "{}", r#"{"token": "prod"}"#)
+        .unwrap();
+    snapshot
+        .apply(
+            &namespace("dev", Some("api/users.http")),
+            "{}",
+            r#"{"id": 7}"#,
+        )
+        .unwrap();
+
+    assert_eq!(
+        snapshot.variables(&namespace("dev", None)),
+        r#"{"token":"dev"}"#
+    );
+    assert_eq!(
+        snapshot.variables(&namespace("prod", None)),
+        r#"{"token":"prod"}"#
+    );
+    assert_eq!(
+        snapshot.variables(&namespace("dev", Some("api/users.http"))),
+        r#"{"id":7}"#
+    );
+    assert_eq!(
+        snapshot.variables(&namespace("prod", Some("api/users.http"))),
+        "{}"
+    );
+
+    let script: Value = serde_json::from_str(&snapshot.to_script().unwrap()).unwrap();
+    assert_eq!(
+        script,
+        json!({
+            "$version": 2,
+            "environments": {
+                "dev": {"variables": {"token": "dev"}, "files": {"api/users.http": {"id": 7}}},
+                "prod": {"variables": {"token": "prod"}}
+            }
+        })
+    );
+    assert_eq!(
+        Snapshot::parse(&script.to_string(), "dev").unwrap(),
+        snapshot
+    );
+}
+
+#[test]
+fn test_apply_changes_only() {
+    let mut snapshot = Snapshot::parse(
+        r#"{"kept": 1, "changed": 1, "removed": 1, "theirs": 1}"#,
+        "dev",
+    )
+    .unwrap();
+
+    snapshot
+        .apply(
+            &namespace("dev", None),
+            r#"{"kept": 1, "changed": 1, "removed": 1}"#,
+            r#"{"kept": 1, "changed": 2, "added": 2}"#,
+        )
+        .unwrap();
+
+    assert_eq!(
+        parsed(&snapshot.variables(&namespace("dev", None))),
+        json!({"added": 2, "changed": 2, "kept": 1, "theirs": 1})
+    );
+}
+
+#[test]
+fn test_apply_drops_empty_namespaces() {
+    let mut snapshot = Snapshot::parse(r#"{"token": "abc"}"#, "dev").unwrap();
+    snapshot
+        .apply(&namespace("dev", Some("api.http")), "{}", "{}")
+        .unwrap();
+    snapshot
+        .apply(&namespace("dev", None), r#"{"token": "abc"}"#, "{}")
+        .unwrap();
+
+    assert_eq!(snapshot, Snapshot::default());
+}
+
+#[test]
+fn test_persist_keeps_concurrent_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(".snapshot.json");
+    std::fs::write(&path, r#"{"token": "old"}"#).unwrap();
+    let dev = namespace("dev", None);
+    let mut first = SnapshotFile::load(&path, "dev").unwrap();
+    let mut second = SnapshotFile::load(&path, "dev").unwrap();
+
+    first
+        .persist(&dev, r#"{"token": "old"}"#, r#"{"token": "new"}"#)
+        .unwrap();
+    second
+        .persist(&dev, r#"{"token": "old"}"#, r#"{"token": "old", "id": 7}"#)
+        .unwrap();
+
+    let snapshot = Snapshot::load(&path, "dev").unwrap();
+    assert_eq!(
+        parsed(&snapshot.variables(&dev)),
+        json!({"id": 7, "token": "new"})
+    );
+    assert_eq!(
+        parsed(&second.variables(&dev)),
+        json!({"id": 7, "token": "new"})
+    );
+    let mut names: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec![".snapshot.json", ".snapshot.json.lock"]);
+}
+
+#[test]
+fn test_persist_waits_for_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(".snapshot.json");
+    let dev = namespace("dev", None);
+    let mut snapshot = SnapshotFile::load(&path, "dev").unwrap();
+    let lock = snapshot.lock().unwrap();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let writer = {
+        let path = path.clone();
+        let dev = dev.clone();
+        std::thread::spawn(move || {
+            let mut other = SnapshotFile::load(&path, "dev").unwrap();
+            other.persist(&dev, "{}", r#"{"id": 1}"#).unwrap();
+            sender.send(()).unwrap();
+        })
+    };
+
+    assert!(receiver
+        .recv_timeout(std::time::Duration::from_millis(200))
+        .is_err());
+    assert!(!path.exists());
+    drop(lock);
+    writer.join().unwrap();
+    snapshot.persist(&dev, "{}", r#"{"token": "abc"}"#).unwrap();
+
+    assert_eq!(
+        parsed(&snapshot.variables(&dev)),
+        json!({"id": 1, "token": "abc"})
+    );
+}
+
+#[test]
+fn test_persist_skips_unchanged_variables() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(".snapshot.json");
+    let dev = namespace("dev", None);
+    let mut snapshot = SnapshotFile::load(&path, "dev").unwrap();
+
+    snapshot
+        .persist(&dev, r#"{"a": 1, "b": 2}"#, r#"{"b": 2, "a": 1}"#)
+        .unwrap();
+    snapshot.sync().unwrap();
+
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    snapshot.persist(&dev, "{}", r#"{"a": 1}"#).unwrap();
+    assert!(snapshot.unsynced);
+    snapshot.sync().unwrap();
+    assert!(!snapshot.unsynced);
+
+    assert_eq!(
+        parsed(&Snapshot::load(&path, "dev").unwrap().variables(&dev)),
+        json!({"a": 1})
+    );
+}
+
+#[test]
+fn test_persist_from_str() {
+    assert_eq!("request".parse::<Persist>().unwrap(), Persist::Request);
+    assert_eq!("success".parse::<Persist>().unwrap(), Persist::Success);
+    assert_eq!(
+        "never".parse::<Persist>().unwrap_err().to_string(),
+        "Unknown persist mode: never"
+    );
+}
+
+#[test]
+fn test_file_key() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("api")).unwrap();
+    let script_file = dir.path().join("api/users.http");
+    std::fs::write(&script_file, "").unwrap();
+    let snapshot_file = dir.path().join(".snapshot.json");
+
+    assert_eq!(file_key(&snapshot_file, &script_file), "api/users.http");
+
+    let elsewhere = tempfile::tempdir().unwrap();
+    let snapshot_file = elsewhere.path().join(".snapshot.json");
+    assert_eq!(
+        file_key(&snapshot_file, &script_file),
+        std::fs::canonicalize(&script_file)
+            .unwrap()
+            .to_string_lossy()
+    );
+}
+
+#[test]
+fn test_scope_from_str() {
+    assert_eq!(
+        "environment".parse::<SnapshotScope>().unwrap(),
+        SnapshotScope::Environment
+    );
+    assert_eq!(
+        "file".parse::<SnapshotScope>().unwrap(),
+        SnapshotScope::File
+    );
+    assert_eq!(
+        "request".parse::<SnapshotScope>().unwrap_err().to_string(),
+        "Unknown snapshot scope: request"
+    );
+}
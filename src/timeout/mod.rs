@@ -0,0 +1,81 @@
+use crate::retry::parse_duration;
+use crate::Result;
+use std::fmt;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+/// How long the client waits on a server before failing the request with a [`Timeout`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// Waiting for the connection to be established
+    pub connect: Option<Duration>,
+    /// Waiting for the response headers, then again for the whole body
+    pub read: Option<Duration>,
+    /// Waiting for the whole request, redirections included
+    pub total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            read: Some(Duration::from_secs(30)),
+            total: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// The timeouts of a `# @timeout 5s` or `# @timeout connect=2s read=10s total=1m` directive,
+    /// the ones it doesn't set are kept, and `0` disables one
+    pub fn apply(&self, value: &str) -> Result<Timeouts> {
+        let mut timeouts = *self;
+        for option in value.split_whitespace() {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, value),
+                None => ("total", option),
+            };
+            let duration = match parse_duration(value)? {
+                Duration::ZERO => None,
+                duration => Some(duration),
+            };
+            match name {
+                "connect" => timeouts.connect = duration,
+                "read" => timeouts.read = duration,
+                "total" => timeouts.total = duration,
+                name => return Err(anyhow!("Unknown timeout: {}", name)),
+            }
+        }
+        Ok(timeouts)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutKind {
+    Connect,
+    Read,
+    Total,
+}
+
+/// The error of a request that timed out, recognised with `error.downcast_ref::<Timeout>()`
+/// whatever context was added to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeout {
+    pub kind: TimeoutKind,
+    pub after: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            TimeoutKind::Connect => "Connecting",
+            TimeoutKind::Read => "Reading the response",
+            TimeoutKind::Total => "The request",
+        };
+        write!(f, "{} timed out after {:?}", what, self.after)
+    }
+}
+
+impl std::error::Error for Timeout {}
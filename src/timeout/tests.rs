@@ -0,0 +1,39 @@
+use super::*;
+
+#[test]
+fn test_apply() {
+    let timeouts = Timeouts::default();
+    assert_eq!(
+        timeouts.apply("5s").unwrap(),
+        Timeouts {
+            total: Some(Duration::from_secs(5)),
+            ..timeouts
+        }
+    );
+    assert_eq!(
+        timeouts.apply("connect=500ms read=0").unwrap(),
+        Timeouts {
+            connect: Some(Duration::from_millis(500)),
+            read: None,
+            total: None,
+        }
+    );
+    assert_eq!(timeouts.apply("").unwrap(), timeouts);
+
+    let error = timeouts.apply("write=1s").unwrap_err();
+    assert_eq!(error.to_string(), "Unknown timeout: write");
+    let error = timeouts.apply("total=soon").unwrap_err();
+    assert_eq!(error.to_string(), "Invalid duration: soon");
+}
+
+#[test]
+fn test_display() {
+    let timeout = Timeout {
+        kind: TimeoutKind::Read,
+        after: Duration::from_millis(1500),
+    };
+    assert_eq!(
+        timeout.to_string(),
+        "Reading the response timed out after 1.5s"
+    );
+}
@@ -0,0 +1,141 @@
+use crate::environment;
+use crate::snapshot::{file_key, Namespace, SnapshotFile};
+use crate::Result;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+mod tests;
+
+/// Where the effective value of a variable comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// An environment of an environment file, `$shared` included
+    Environment { file: PathBuf, env: String },
+    /// The global variables of the snapshot, those of a script file when scoped by file
+    Snapshot { file: Option<String> },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Environment { file, env } => {
+                let name = file.file_name().unwrap_or(file.as_os_str());
+                write!(f, "{} {}", name.to_string_lossy(), env)
+            }
+            Source::Snapshot { file: None } => write!(f, "snapshot"),
+            Source::Snapshot { file: Some(file) } => write!(f, "snapshot {}", file),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub value: Value,
+    pub source: Source,
+}
+
+/// The variables scripts see for an environment, resolved like `client.global.get`: the snapshot
+/// first, then the environment files
+pub struct Variables {
+    env_file: PathBuf,
+    snapshot: SnapshotFile,
+    namespace: Namespace,
+}
+
+impl Variables {
+    /// The variables of `env`, with the snapshot variables of `script_file` when given, as
+    /// `--snapshot-scope file` keeps them
+    pub fn new(
+        env_file: &Path,
+        snapshot_file: &Path,
+        env: &str,
+        script_file: Option<&Path>,
+    ) -> Result<Variables> {
+        Ok(Variables {
+            env_file: env_file.to_path_buf(),
+            snapshot: SnapshotFile::load(snapshot_file, env)?,
+            namespace: Namespace {
+                env: env.to_string(),
+                file: script_file.map(|script_file| file_key(snapshot_file, script_file)),
+            },
+        })
+    }
+
+    /// The effective value of every variable, sorted by name
+    pub fn list(&self) -> Result<Vec<Variable>> {
+        let mut variables = BTreeMap::new();
+        let mut set = |name: &String, value: &Value, source: &Source| {
+            variables.insert(
+                name.clone(),
+                Variable {
+                    name: name.clone(),
+                    value: value.clone(),
+                    source: source.clone(),
+                },
+            );
+        };
+        for (file, env, layer) in environment::layers(&self.env_file, &self.namespace.env)? {
+            let source = Source::Environment { file, env };
+            for (name, value) in layer.as_object().into_iter().flatten() {
+                set(name, value, &source);
+            }
+        }
+        let source = Source::Snapshot {
+            file: self.namespace.file.clone(),
+        };
+        // `client.global.get` skips snapshot variables that are null
+        for (name, value) in &self.snapshot_variables()? {
+            if !value.is_null() {
+                set(name, value, &source);
+            }
+        }
+        Ok(variables.into_values().collect())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Variable>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .find(|variable| variable.name == name))
+    }
+
+    /// Sets a snapshot variable, which takes precedence over the environment files
+    pub fn set(&mut self, name: &str, value: Value) -> Result<()> {
+        let before = self.snapshot_variables()?;
+        let mut after = before.clone();
+        after.insert(name.to_string(), value);
+        self.persist(before, after)
+    }
+
+    /// Removes a snapshot variable, the environment files may still define it
+    pub fn unset(&mut self, name: &str) -> Result<()> {
+        let before = self.snapshot_variables()?;
+        let mut after = before.clone();
+        after.remove(name);
+        self.persist(before, after)
+    }
+
+    /// Removes every snapshot variable of the environment, or of the script file
+    pub fn clear(&mut self) -> Result<()> {
+        let before = self.snapshot_variables()?;
+        self.persist(before, Map::new())
+    }
+
+    fn snapshot_variables(&self) -> Result<Map<String, Value>> {
+        Ok(serde_json::from_str(
+            &self.snapshot.variables(&self.namespace),
+        )?)
+    }
+
+    fn persist(&mut self, before: Map<String, Value>, after: Map<String, Value>) -> Result<()> {
+        self.snapshot.persist(
+            &self.namespace,
+            &Value::Object(before).to_string(),
+            &Value::Object(after).to_string(),
+        )
+    }
+}
@@ -0,0 +1,122 @@
+use super::*;
+use serde_json::json;
+
+fn files(dir: &Path) -> (PathBuf, PathBuf) {
+    let env_file = dir.join("http-client.env.json");
+    std::fs::write(
+        &env_file,
+        r#"{"$shared": {"host": "shared", "page": 1}, "dev": {"host": "dev", "token": "public"}}"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("http-client.private.env.json"),
+        r#"{"$shared": {"page": 2}, "dev": {"token": "private"}}"#,
+    )
+    .unwrap();
+    let snapshot_file = dir.join(".snapshot.json");
+    std::fs::write(&snapshot_file, r#"{"token": "captured", "empty": null}"#).unwrap();
+    (env_file, snapshot_file)
+}
+
+fn environment(file: &Path, env: &str) -> Source {
+    Source::Environment {
+        file: file.to_path_buf(),
+        env: env.to_string(),
+    }
+}
+
+#[test]
+fn test_list() {
+    let dir = tempfile::tempdir().unwrap();
+    let (env_file, snapshot_file) = files(dir.path());
+    let private_file = dir.path().join("http-client.private.env.json");
+
+    let variables = Variables::new(&env_file, &snapshot_file, "dev", None)
+        .unwrap()
+        .list()
+        .unwrap();
+
+    assert_eq!(
+        variables,
+        vec![
+            Variable {
+                name: "host".to_string(),
+                value: json!("dev"),
+                source: environment(&env_file, "dev"),
+            },
+            Variable {
+                name: "page".to_string(),
+                value: json!(2),
+                source: environment(&private_file, "$shared"),
+            },
+            Variable {
+                name: "token".to_string(),
+                value: json!("captured"),
+                source: Source::Snapshot { file: None },
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_set_unset_clear() {
+    let dir = tempfile::tempdir().unwrap();
+    let (env_file, snapshot_file) = files(dir.path());
+    let mut variables = Variables::new(&env_file, &snapshot_file, "dev", None).unwrap();
+
+    variables.set("id", json!({"a": 1})).unwrap();
+    variables.unset("token").unwrap();
+
+    let reloaded = Variables::new(&env_file, &snapshot_file, "dev", None).unwrap();
+    assert_eq!(
+        reloaded.get("id").unwrap().map(|variable| variable.value),
+        Some(json!({"a": 1}))
+    );
+    assert_eq!(
+        reloaded
+            .get("token")
+            .unwrap()
+            .map(|variable| variable.source),
+        Some(environment(
+            &dir.path().join("http-client.private.env.json"),
+            "dev"
+        ))
+    );
+
+    variables.clear().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&snapshot_file).unwrap(),
+        r#"{"$version":2,"environments":{}}"#
+    );
+}
+
+#[test]
+fn test_file_variables() {
+    let dir = tempfile::tempdir().unwrap();
+    let (env_file, snapshot_file) = files(dir.path());
+    let script_file = dir.path().join("api.http");
+    std::fs::write(&script_file, "").unwrap();
+    let mut variables =
+        Variables::new(&env_file, &snapshot_file, "dev", Some(&script_file)).unwrap();
+
+    variables.set("id", json!(7)).unwrap();
+
+    let id = variables.get("id").unwrap().unwrap();
+    assert_eq!(id.value, json!(7));
+    assert_eq!(id.source.to_string(), "snapshot api.http");
+    assert_eq!(
+        variables.get("token").unwrap().unwrap().value,
+        json!("private")
+    );
+    let environment = Variables::new(&env_file, &snapshot_file, "dev", None).unwrap();
+    assert_eq!(environment.get("id").unwrap(), None);
+}
+
+#[test]
+fn test_source_display() {
+    assert_eq!(
+        environment(Path::new("/api/http-client.env.json"), "$shared").to_string(),
+        "http-client.env.json $shared"
+    );
+    assert_eq!(Source::Snapshot { file: None }.to_string(), "snapshot");
+}
@@ -1,7 +1,7 @@
 use crate::common::{create_file, DebugWriter};
 use dot_http::output::parse_format;
 use dot_http::output::print::FormattedOutputter;
-use dot_http::{ClientConfig, Runtime};
+use dot_http::{ClientConfig, Runtime, ScriptConfig};
 use httpmock::MockServer;
 use std::borrow::BorrowMut;
 
@@ -38,6 +38,7 @@ fn simple_get() {
         &env_file,
         outputter.borrow_mut(),
         ClientConfig::default(),
+        ScriptConfig::default(),
     )
     .unwrap();
     runtime.execute(&script_file, 1, false).unwrap();
@@ -97,6 +98,7 @@ POST http://localhost:{port}/simple_post
         &env_file,
         outputter.borrow_mut(),
         ClientConfig::default(),
+        ScriptConfig::default(),
     )
     .unwrap();
 
@@ -121,3 +123,428 @@ content-length: 15\
         )
     );
 }
+
+#[test]
+fn signed_post() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::POST)
+            .path("/signed_post")
+            .header(
+                "X-Signature",
+                "5d98b45c90a207fa998ce639fea6f02ecc8cc3f36fef81d694fb856b4d0a28ca",
+            );
+        then.status(200).header("date", "");
+    });
+
+    let env = "dev";
+
+    let snapshot_file = create_file("{}");
+    let env_file = create_file(r#"{"dev": {"secret": "key"}}"#);
+    let script_file = create_file(&format!(
+        "\
+< {{%
+    client.global.set('signature', crypto.hmac.sha256()
+        .withTextSecret(secret)
+        .updateWithText('payload')
+        .digest()
+        .toHex());
+%}}
+POST http://localhost:{port}/signed_post
+X-Signature: {{{{client.global.get('signature')}}}}
+
+payload\
+        ",
+        port = server.port(),
+    ));
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n").unwrap(),
+        parse_format("%R\n").unwrap(),
+    );
+
+    let mut runtime = Runtime::new(
+        env,
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig::default(),
+    )
+    .unwrap();
+
+    runtime.execute(&script_file, 1, false).unwrap();
+
+    mock.assert();
+}
+
+#[test]
+fn external_handlers() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::GET)
+            .path("/external")
+            .header("X-Signature", "signed");
+        then.status(200)
+            .header("date", "")
+            .header("content-type", "application/json")
+            .body(r#"{"token": "abc"}"#);
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("pre")).unwrap();
+    std::fs::create_dir(dir.path().join("handlers")).unwrap();
+    std::fs::write(
+        dir.path().join("pre/sign.js"),
+        "client.global.set('signature', 'signed');\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("handlers/extract-token.js"),
+        "client.global.set('token', response.body.token);\n",
+    )
+    .unwrap();
+    let script_file = dir.path().join("api.http");
+    std::fs::write(
+        &script_file,
+        format!(
+            "\
+< ./pre/sign.js
+GET http://localhost:{port}/external
+X-Signature: {{{{signature}}}}
+
+> ./handlers/extract-token.js
+",
+            port = server.port(),
+        ),
+    )
+    .unwrap();
+
+    let snapshot_file = create_file("{}");
+    let env_file = create_file("{}");
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n").unwrap(),
+        parse_format("%R\n").unwrap(),
+    );
+
+    let mut runtime = Runtime::new(
+        "dev",
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig::default(),
+    )
+    .unwrap();
+
+    runtime.execute(&script_file, 1, false).unwrap();
+
+    mock.assert();
+    let snapshot = std::fs::read_to_string(&snapshot_file).unwrap();
+    assert!(snapshot.contains(r#""token":"abc""#), "{}", snapshot);
+}
+
+#[test]
+fn external_handler_error() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(httpmock::Method::GET).path("/external_error");
+        then.status(200).header("date", "");
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("broken.js"), "\n\nundefinedFunction();\n").unwrap();
+    let script_file = dir.path().join("api.http");
+    std::fs::write(
+        &script_file,
+        format!(
+            "\
+GET http://localhost:{port}/external_error
+
+> ./broken.js
+",
+            port = server.port(),
+        ),
+    )
+    .unwrap();
+
+    let snapshot_file = create_file("{}");
+    let env_file = create_file("{}");
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n").unwrap(),
+        parse_format("%R\n").unwrap(),
+    );
+
+    let mut runtime = Runtime::new(
+        "dev",
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig::default(),
+    )
+    .unwrap();
+
+    let error = runtime.execute(&script_file, 1, false).unwrap_err();
+    assert!(
+        format!("{:#}", error).contains(&dir.path().join("broken.js").display().to_string()),
+        "{:#}",
+        error
+    );
+}
+
+#[test]
+fn external_handler_syntax_error() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(httpmock::Method::GET)
+            .path("/external_syntax_error");
+        then.status(200).header("date", "");
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("broken.js"),
+        "client.global.set('seen', true);\n\nvar = 1;\n",
+    )
+    .unwrap();
+    let script_file = dir.path().join("api.http");
+    std::fs::write(
+        &script_file,
+        format!(
+            "\
+GET http://localhost:{port}/external_syntax_error
+
+> ./broken.js
+",
+            port = server.port(),
+        ),
+    )
+    .unwrap();
+
+    let snapshot_file = create_file("{}");
+    let env_file = create_file("{}");
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n").unwrap(),
+        parse_format("%R\n").unwrap(),
+    );
+
+    let mut runtime = Runtime::new(
+        "dev",
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig::default(),
+    )
+    .unwrap();
+
+    let error = runtime.execute(&script_file, 1, false).unwrap_err();
+    let location = format!("{}:3:", dir.path().join("broken.js").display());
+    assert!(format!("{:#}", error).contains(&location), "{:#}", error);
+}
+
+#[test]
+fn handler_process_env() {
+    let server = MockServer::start();
+    server.mock(|when, then| {
+        when.method(httpmock::Method::GET)
+            .path("/handler_process_env");
+        then.status(200).header("date", "");
+    });
+
+    let snapshot_file = create_file("{}");
+    let env_file = create_file("{}");
+    let script_file = create_file(&format!(
+        "\
+GET http://localhost:{port}/handler_process_env
+
+> {{%
+client.global.set('branch', $processEnv.DOT_HTTP_TEST_UNSET_BRANCH ?? 'main');
+client.global.set('label', '$processEnv.DOT_HTTP_TEST_UNSET_BRANCH');
+%}}
+",
+        port = server.port(),
+    ));
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n").unwrap(),
+        parse_format("%R\n").unwrap(),
+    );
+
+    let mut runtime = Runtime::new(
+        "dev",
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig::default(),
+    )
+    .unwrap();
+
+    runtime.execute(&script_file, 1, false).unwrap();
+    let snapshot: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&snapshot_file).unwrap()).unwrap();
+    let globals = &snapshot["environments"]["dev"]["variables"];
+    assert_eq!(globals["branch"], "main");
+    assert_eq!(globals["label"], "$processEnv.DOT_HTTP_TEST_UNSET_BRANCH");
+
+    std::fs::write(
+        &script_file,
+        format!(
+            "\
+GET http://localhost:{port}/handler_process_env
+
+> {{%
+client.global.set('branch', $processEnv.DOT_HTTP_TEST_UNSET_BRANCH);
+%}}
+",
+            port = server.port(),
+        ),
+    )
+    .unwrap();
+    let error = runtime.execute(&script_file, 1, false).unwrap_err();
+    assert!(
+        format!("{:#}", error)
+            .contains("The environment variable DOT_HTTP_TEST_UNSET_BRANCH is not set"),
+        "{:#}",
+        error
+    );
+}
+
+#[test]
+fn secrets() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::GET)
+            .path("/secrets")
+            .header("Authorization", "Bearer s3cr3t");
+        then.status(200)
+            .header("date", "")
+            .header("content-type", "application/json")
+            .body(r#"{"token": "s3cr3t", "id": 7}"#);
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+    std::fs::write(
+        &env_file,
+        r#"{"dev": {}, "secrets": {"token": {"command": "echo s3cr3t"}}}"#,
+    )
+    .unwrap();
+    let script_file = dir.path().join("api.http");
+    std::fs::write(
+        &script_file,
+        format!(
+            "\
+GET http://localhost:{port}/secrets
+Authorization: Bearer {{{{$secret token}}}}
+
+> {{%
+client.global.set('token', response.body.token);
+client.global.set('id', response.body.id);
+%}}
+",
+            port = server.port(),
+        ),
+    )
+    .unwrap();
+
+    let snapshot_file = dir.path().join(".snapshot.json");
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n%H\n").unwrap(),
+        parse_format("%B\n").unwrap(),
+    );
+
+    let mut runtime = Runtime::new(
+        "dev",
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig::default(),
+    )
+    .unwrap();
+
+    runtime.execute(&script_file, 1, false).unwrap();
+
+    mock.assert();
+    let DebugWriter(buf) = writer;
+    assert!(!buf.contains("s3cr3t"), "{}", buf);
+    assert!(buf.contains("Authorization: Bearer ********"), "{}", buf);
+    let snapshot = std::fs::read_to_string(&snapshot_file).unwrap();
+    assert!(!snapshot.contains("s3cr3t"), "{}", snapshot);
+    assert!(snapshot.contains(r#""id":7"#), "{}", snapshot);
+}
+
+#[test]
+fn overrides() {
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::GET)
+            .path("/users/42")
+            .header("X-Filter", r#"{"a":1}"#);
+        then.status(200).header("date", "").body("{}");
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let env_file = dir.path().join("http-client.env.json");
+    std::fs::write(&env_file, r#"{"dev": {"userId": 7}}"#).unwrap();
+    let script_file = dir.path().join("api.http");
+    std::fs::write(
+        &script_file,
+        format!(
+            "\
+GET http://localhost:{port}/users/{{{{userId}}}}
+X-Filter: {{{{JSON.stringify(filter)}}}}
+
+> {{%
+client.global.set('userId', 8);
+%}}
+",
+            port = server.port(),
+        ),
+    )
+    .unwrap();
+
+    let snapshot_file = dir.path().join(".snapshot.json");
+    let writer = &mut DebugWriter(String::new());
+    let mut outputter = FormattedOutputter::new(
+        writer,
+        parse_format("%R\n").unwrap(),
+        parse_format("").unwrap(),
+    );
+
+    let mut overrides = serde_json::Map::new();
+    overrides.insert("userId".to_string(), serde_json::json!("42"));
+    overrides.insert("filter".to_string(), serde_json::json!({"a": 1}));
+    let mut runtime = Runtime::new(
+        "dev",
+        &snapshot_file,
+        &env_file,
+        outputter.borrow_mut(),
+        ClientConfig::default(),
+        ScriptConfig {
+            overrides,
+            ..ScriptConfig::default()
+        },
+    )
+    .unwrap();
+
+    runtime.execute(&script_file, 1, false).unwrap();
+
+    mock.assert();
+    let snapshot = std::fs::read_to_string(&snapshot_file).unwrap();
+    assert!(snapshot.contains(r#""userId":8"#), "{}", snapshot);
+    assert!(!snapshot.contains("filter"), "{}", snapshot);
+}